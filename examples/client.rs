@@ -1,37 +1,75 @@
 use std::{mem, thread};
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
+use std::io::Read;
 use std::os::fd::AsRawFd;
 use std::time::Duration;
 
-use raqote::Color;
+use raqote::DrawTarget;
 use syscall::PAGE_SIZE;
 
-fn main() {
-    let win = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open("comp:title=Client&min-size=200,160")
-        .expect("Unable to create window");
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct RawEvent {
+    kind: u32,
+    a: i32,
+    b: i32,
+    c: i32,
+}
 
-    let mut ctx = unsafe {
-        let ptr = syscall::fmap(win.as_raw_fd() as usize, &syscall::Map {
+const EVENT_RESIZE: u32 = 6;
+
+const BUFFERS: usize = 2;
+
+/// Maps the frame's buffers. This has to be repeated whenever the frame is resized, as the buffers are reallocated.
+fn map(win: &File, width: i32, height: i32) -> Vec<DrawTarget<&'static mut [u32]>> {
+    let stride = (((width * height * 4) as usize).max(1) + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1);
+
+    let ptr = unsafe {
+        syscall::fmap(win.as_raw_fd() as usize, &syscall::Map {
             offset: 0,
-            size: ((200 * 160 * 4) as usize + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1),
+            size: stride * BUFFERS,
             flags: syscall::PROT_READ | syscall::PROT_WRITE,
             address: 0,
-        }).unwrap();
-
-        let buffer = std::slice::from_raw_parts_mut(ptr as *mut u32, 200 * 160);
-        raqote::DrawTarget::from_backing(200, 160, buffer)
+        }).unwrap()
     };
 
-    ctx.clear(raqote::SolidSource { r: 0xff, g: 0xff, b: 0xff, a: 0xff });
+    (0..BUFFERS)
+        .map(|i| unsafe {
+            let buffer = std::slice::from_raw_parts_mut((ptr + i * stride) as *mut u32, (width * height) as usize);
+            DrawTarget::from_backing(width, height, buffer)
+        })
+        .collect()
+}
 
-    syscall::fsync(win.as_raw_fd() as usize).unwrap();
+fn main() {
+    let mut win = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("comp:title=Client&min-size=200,160&resize&buffers=2")
+        .expect("Unable to create window");
+
+    let mut buffers = map(&win, 200, 160);
+    let mut back = 1;
+
+    buffers[back].clear(raqote::SolidSource { r: 0xff, g: 0xff, b: 0xff, a: 0xff });
+
+    back = syscall::fsync(win.as_raw_fd() as usize).unwrap();
 
     loop {
-        ctx.clear(raqote::SolidSource::from_unpremultiplied_argb(0xff, 0xff, 0x00, 0xff));
-        syscall::fsync(win.as_raw_fd() as usize).unwrap();
-        thread::sleep(Duration::from_millis(16000));
+        let mut events = [RawEvent::default(); 16];
+        if let Ok(count) = win.read(unsafe {
+            std::slice::from_raw_parts_mut(events.as_mut_ptr() as *mut u8, mem::size_of_val(&events))
+        }) {
+            for event in &events[..count / mem::size_of::<RawEvent>()] {
+                if event.kind == EVENT_RESIZE {
+                    buffers = map(&win, event.a, event.b);
+                    back = 1;
+                }
+            }
+        }
+
+        buffers[back].clear(raqote::SolidSource::from_unpremultiplied_argb(0xff, 0xff, 0x00, 0xff));
+        back = syscall::fsync(win.as_raw_fd() as usize).unwrap();
+        thread::sleep(Duration::from_millis(16));
     }
 }
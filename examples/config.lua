@@ -0,0 +1,56 @@
+-- Pass with `comp --config examples/config.lua`, or set COMP_CONFIG
+
+displays = {
+    { path = "display:3/activate", x = 0, y = 0 },
+    -- { path = "memory:1920x1080", x = 0, y = 0 }, -- headless
+}
+
+plugins = { "examples/plugins/example3.lua" }
+-- plugins can only change frames they created, unless given the "frames" capability.
+-- "input" lets them see the mouse and keyboard, and "overlays" lets them paint above frames.
+-- A plugin with a manifest (eg. example3.manifest.lua) only gets those it lists there too.
+capabilities = { ["examples/plugins/example3.lua"] = { "frames" } }
+-- plugins exceeding these are unloaded. "stdlib" gives a plugin `io`, `os` and `package`
+plugin_limits = { memory = 64 * 1024 * 1024, instructions = 10000000 }
+-- reload plugins when their files change
+watch_plugins = false
+
+input = "input:consumer"
+focus = "click"
+
+frame = {
+    size = { width = 640, height = 480 },
+    can_close = true,
+}
+
+colors = {
+    background = 0xff000000,
+    frame = 0xffaaaaaa,
+}
+
+max_fps = 60
+
+-- remove to let clients draw their own decorations
+decorations = {
+    font = "/ui/fonts/Sans/Fira/Regular.ttf",
+    font_size = 14,
+    title_height = 24,
+    border = 1,
+    colors = {
+        title = 0xff303030,
+        focused_title = 0xff3c5a8c,
+        text = 0xffffffff,
+        border = 0xff202020,
+    },
+}
+
+-- caps on what a single user's clients, or a single plugin, may allocate
+limits = {
+    frames = 256,
+    memory = 512 * 1024 * 1024,
+    size = { width = 16384, height = 16384 },
+    users = {
+        [1000] = { frames = 64, memory = 256 * 1024 * 1024 },
+    },
+    plugins = { frames = 32, memory = 128 * 1024 * 1024, size = { width = 8192, height = 8192 } },
+}
@@ -39,7 +39,7 @@ fn main() {
 
     surface.fill(0xffffffff);
 
-    file.write(unsafe {
+    file.write_all(unsafe {
         slice::from_raw_parts(
             &(SyncRect {
                 x: 0,
@@ -1,30 +1,95 @@
-use std::mem;
-use std::alloc::{self, Layout, alloc};
+use std::{mem, slice};
+use std::alloc::{Layout, alloc, dealloc};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use syscall::PAGE_SIZE;
 
-pub fn aligned_vec(width: i32, height: i32) -> Vec<u32> {
-    #[repr(C, align(4096))]
-    #[derive(Clone)]
-    struct Vector([u32; PAGE_SIZE / mem::size_of::<u32>()]);
+/// The bytes currently held by every `PageBuffer`
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
 
-    let pages = ((width * height) as usize * mem::size_of::<u32>()) / mem::size_of::<Vector>() + 1;
-    let mut vec = vec![Vector([0xffaaaaaa; PAGE_SIZE / mem::size_of::<u32>()]); pages];
+/// The total size of the pixel memory currently allocated, in bytes
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// Page-aligned memory, rounded up to whole pages so clients can map it. Freed when dropped.
+pub struct PageBuffer {
+    ptr: NonNull<u32>,
+    layout: Layout,
+}
+
+impl PageBuffer {
+    /// Allocates room for at least `len` pixels, each set to `fill`. Fails with `ENOMEM` if the memory can't be allocated.
+    pub fn new(len: usize, fill: u32) -> syscall::Result<PageBuffer> {
+        let layout = len.checked_mul(mem::size_of::<u32>())
+            .and_then(|bytes| bytes.max(1).checked_add(PAGE_SIZE - 1))
+            .and_then(|bytes| Layout::from_size_align(bytes & !(PAGE_SIZE - 1), PAGE_SIZE).ok())
+            .ok_or(syscall::Error::new(syscall::ENOMEM))?;
+
+        let Some(ptr) = NonNull::new(unsafe { alloc(layout) } as *mut u32) else {
+            eprintln!("Unable to allocate {} bytes of frame memory, with {} allocated already", layout.size(), allocated());
+            return Err(syscall::Error::new(syscall::ENOMEM));
+        };
+
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+
+        let mut buffer = Self { ptr, layout };
+        buffer.as_mut_slice().fill(fill);
+        Ok(buffer)
+    }
+
+    /// The size of the allocation, which is always a whole number of pages
+    pub fn bytes(&self) -> usize {
+        self.layout.size()
+    }
 
-    let ptr = vec.as_mut_ptr() as *mut u32;
-    let len = (width * height) as usize;
-    mem::forget(vec);
+    pub fn as_mut_ptr(&mut self) -> *mut u32 {
+        self.ptr.as_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u32] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.bytes() / mem::size_of::<u32>()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.bytes() / mem::size_of::<u32>()) }
+    }
+
+    /// Borrows `len` pixels starting at `offset` without tying them to the buffer's lifetime.
+    ///
+    /// # Safety
+    /// The view must be dropped before the buffer, and must not overlap any other view in use.
+    pub unsafe fn view(&mut self, offset: usize, len: usize) -> BufferView {
+        assert!(offset + len <= self.bytes() / mem::size_of::<u32>(), "view out of bounds");
+
+        BufferView {
+            ptr: self.ptr.as_ptr().add(offset),
+            len,
+        }
+    }
+}
+
+impl Drop for PageBuffer {
+    fn drop(&mut self) {
+        ALLOCATED.fetch_sub(self.bytes(), Ordering::Relaxed);
+        unsafe { dealloc(self.ptr.as_ptr() as *mut u8, self.layout) };
+    }
+}
 
-    unsafe { Vec::from_raw_parts(ptr, len, len) }
+/// Part of a `PageBuffer`, which a `DrawTarget` can draw into
+pub struct BufferView {
+    ptr: *mut u32,
+    len: usize,
 }
 
-pub fn aligned_vec_new(width: i32, height: i32) -> Vec<u32> {
-    unsafe { alloc_vec::<u32>((width * height) as usize) }
+impl AsRef<[u32]> for BufferView {
+    fn as_ref(&self) -> &[u32] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
 }
 
-pub unsafe fn alloc_vec<T>(len: usize) -> Vec<T> {
-    let bytes = len * mem::size_of::<T>() + (PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
-    let layout = Layout::from_size_align(bytes, PAGE_SIZE)
-        .unwrap();
-    let ptr = alloc(layout);
-    Vec::from_raw_parts(ptr as *mut T, len, len)
+impl AsMut<[u32]> for BufferView {
+    fn as_mut(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
 }
@@ -13,26 +13,63 @@ use std::time::{Duration, Instant};
 
 use euclid::{Box2D, Size2D, UnknownUnit};
 use lazy_static::lazy_static;
-use raqote::{DrawTarget, IntPoint, IntRect, SolidSource};
+use raqote::{BlendMode, DrawOptions, DrawTarget, IntPoint, IntRect};
 use raqote::Source::Solid;
 use syscall::{Map, O_NONBLOCK, Packet, SchemeMut};
 
-use crate::config::Config;
+use crate::config::{solid_source, Capability, Config, Limits};
+use crate::damage::Damage;
+use crate::decoration::{Button, Decorations, Hit};
 use crate::display::Display;
-use crate::frame::{Frame, FrameEvent, FrameOptions};
+use crate::event::{ClientEvent, RawEvent};
+use crate::focus::{Drag, DragKind, Edges, Focus, FocusMode};
+use crate::frame::{Buffers, Frame, FrameEvent, FrameOptions, FrameRequest, Owner};
+use crate::input::{Input, InputEvent, BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT, SCANCODE_SUPER};
+use crate::overlay::Overlay;
 use crate::plugin;
 use crate::plugin::{PluginEvent, PluginManager};
+use crate::stack::Stack;
 
-pub struct Compositor<'a, 'b> {
-    pub displays: Vec<Display<'a>>,
+pub struct Compositor {
+    pub displays: Vec<Display>,
 
-    pub frames: HashMap<usize, Frame<'b>>,
+    pub frames: HashMap<usize, Frame>,
+
+    pub stack: Stack,
+
+    pub overlays: Vec<Overlay>,
+
+    pub decorations: Option<Decorations>,
 
     pub surface: DrawTarget,
 
     pub cursor: IntPoint,
 
-    pub scheme: File,
+    pub focus: Focus,
+
+    input: Option<Input>,
+
+    /// The socket clients open frames through. Headless compositors have none.
+    pub scheme: Option<File>,
+
+    pub config: Config,
+
+    /// The client which opened each scheme handle
+    pub handles: HashMap<usize, Owner>,
+
+    /// Events left unread by handles whose frame was destroyed while they were open
+    orphaned: HashMap<usize, VecDeque<ClientEvent>>,
+
+    /// The id the next frame gets. Ids are never reused, so stale handles can't reach another client's frame.
+    next_id: usize,
+
+    /// The process which sent the packet being handled
+    caller_pid: usize,
+
+    /// The capabilities of each loaded plugin, by path
+    pub plugin_capabilities: HashMap<String, Vec<Capability>>,
+
+    damage: Damage,
 
     last_update: Instant,
 
@@ -40,54 +77,107 @@ pub struct Compositor<'a, 'b> {
 }
 
 pub const SCHEME_NAME: &'static str = ":comp";
-pub const MAX_FPS: Duration = Duration::from_nanos(1_000_000_000 / 60);
 
-impl<'a, 'b> Compositor<'a, 'b> {
+impl Compositor {
     pub fn new(config: Config) -> Result<(Self, Rc<Mutex<VecDeque<PluginEvent>>>), String> {
+        Self::with_scheme(config, Some(open_scheme()?))
+    }
+
+    /// Without a scheme, no clients can connect, so frames can only be created through `apply`. Useful with `memory:` displays.
+    pub fn with_scheme(config: Config, scheme: Option<File>) -> Result<(Self, Rc<Mutex<VecDeque<PluginEvent>>>), String> {
         let displays: Vec<Display> = config.displays.iter()
-            .map(|(name, pos)| Display::new(&name, &pos)
-                .expect("Failed to create display"))
-            .collect();
+            .map(|(name, pos)| Display::new(&name, &pos))
+            .collect::<Result<_, _>>()?;
 
         println!("Created {} displays", displays.len());
 
-        let mut min = (0, 0);
-        let mut max = (0, 0);
-
-        displays.iter().for_each(|i| {
-            min = (i.pos.x.min(min.0), i.pos.y.min(min.1));
-            max = ((i.pos.x + i.surface.width()).max(max.0), (i.pos.y + i.surface.height()).max(max.1));
-        });
+        let size = surface_size(&displays);
+        let input = open_input(&config);
 
         let events = Rc::new(Mutex::new(VecDeque::new()));
 
+        let mut damage = Damage::new();
+        damage.add(IntRect::from_size(size));
+
         Ok((Compositor {
-            last_update: Instant::now() - MAX_FPS,
+            last_update: Instant::now() - config.frame_time(),
             events: Rc::clone(&events),
             displays,
             frames: HashMap::new(),
-            surface: DrawTarget::new(max.0 - min.0, max.1 - min.1),
+            stack: Stack::new(),
+            overlays: Vec::new(),
+            decorations: load_decorations(&config),
+            damage,
+            surface: DrawTarget::new(size.width, size.height),
             cursor: IntPoint::new(0, 0),
-            scheme: syscall::open(SCHEME_NAME, syscall::O_CREAT | syscall::O_RDWR | syscall::O_CLOEXEC | O_NONBLOCK)
-                .map(|socket| unsafe { File::from_raw_fd(socket as RawFd) })
-                .unwrap_or_else(|_| {
-                    syscall::open(SCHEME_NAME, syscall::O_RDWR | syscall::O_CLOEXEC | O_NONBLOCK)
-                        .map(|socket| unsafe { File::from_raw_fd(socket as RawFd) })
-                        .unwrap()
-                }),
+            focus: Focus::new(config.focus_mode),
+            config,
+            handles: HashMap::new(),
+            orphaned: HashMap::new(),
+            next_id: 1,
+            caller_pid: 0,
+            plugin_capabilities: HashMap::new(),
+            input,
+            scheme,
         }, Rc::clone(&events)))
     }
 
+    /// Switches to a new config without disturbing any frames.
+    /// Displays are matched by name, so displays present in both configs are only repositioned.
+    pub fn apply_config(&mut self, config: Config) {
+        self.displays.retain(|display| config.displays.iter().any(|(name, _)| *name == display.name));
+
+        for (name, pos) in config.displays.iter() {
+            match self.displays.iter_mut().find(|i| i.name == *name) {
+                Some(display) => display.pos = *pos,
+                None => match Display::new(name, pos) {
+                    Ok(display) => self.displays.push(display),
+                    Err(err) => eprintln!("Failed to create display: {}", err)
+                }
+            }
+        }
+
+        let size = surface_size(&self.displays);
+        if size != Size2D::new(self.surface.width(), self.surface.height()) {
+            self.surface = DrawTarget::new(size.width, size.height);
+            let cursor = self.cursor;
+            self.move_cursor(cursor);
+        }
+
+        if config.input != self.config.input {
+            self.input = open_input(&config);
+        }
+
+        self.decorations = load_decorations(&config);
+
+        self.focus.mode = config.focus_mode;
+        self.config = config;
+
+        // pending damage may lie outside the new surface, and everything is redrawn anyway
+        self.damage.take();
+        self.damage(IntRect::from_size(size));
+    }
+
     pub fn tick(&mut self) {
-        if self.last_update.elapsed() < MAX_FPS {
+        if self.last_update.elapsed() < self.config.frame_time() {
             return;
         }
 
+        self.focus.released.clear();
+        self.focus.scroll = (0, 0);
+
+        let input = self.input.as_mut().map(|i| i.poll()).unwrap_or_default();
+        input.into_iter().for_each(|i| self.handle_input(i));
+
         let mut packet = Packet::default();
-        if let Ok(len) = self.scheme.read(&mut packet) {
+        if let Some(Ok(len)) = self.scheme.as_mut().map(|scheme| scheme.read(&mut packet)) {
             if len > 0 {
+                self.caller_pid = packet.pid;
                 self.handle(&mut packet);
-                self.scheme.write(&packet).unwrap();
+
+                if let Some(Err(err)) = self.scheme.as_mut().map(|scheme| scheme.write_all(&packet)) {
+                    eprintln!("Failed to answer request: {}", err);
+                }
             }
         }
 
@@ -95,83 +185,905 @@ impl<'a, 'b> Compositor<'a, 'b> {
         self.last_update = Instant::now();
     }
 
+    /// Routes an input event to plugins and to the frame it concerns
+    pub fn handle_input(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Mouse { x, y } => self.move_cursor(IntPoint::new(x, y)),
+            InputEvent::MouseRelative { dx, dy } => self.move_cursor(self.cursor + IntPoint::new(dx, dy).to_vector()),
+            InputEvent::Buttons(buttons) => {
+                let changed = buttons ^ self.focus.buttons;
+                self.focus.buttons = buttons;
+
+                for button in [BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT] {
+                    if changed & button == 0 {
+                        continue;
+                    }
+
+                    let pressed = buttons & button != 0;
+
+                    self.events.lock().unwrap().push_back(match pressed {
+                        true => PluginEvent::OnMouseDown(button),
+                        false => PluginEvent::OnMouseUp(button),
+                    });
+
+                    if pressed {
+                        let hovered = self.focus.hovered;
+                        self.focus_frame(hovered);
+                        if let Some(id) = hovered {
+                            let _ = self.raise_frame(id);
+
+                            // presses on decorations, or with the modifier held, are handled by the compositor rather than the client
+                            if self.focus.grab.is_none() && self.focus.drag.is_none() {
+                                if self.focus.modifier {
+                                    self.press_modified(id, button);
+                                    continue;
+                                }
+
+                                if let Some(hit) = self.decoration_hit(id, self.cursor) {
+                                    self.press_decoration(id, hit, button);
+                                    continue;
+                                }
+                            }
+                        }
+                        self.focus.grab = self.focus.grab.or(hovered);
+                    }
+
+                    if let Some(id) = self.focus.pointer_target() {
+                        let _ = self.send_event(id, ClientEvent::MouseButton { button, pressed });
+                    }
+                }
+
+                if buttons == 0 {
+                    self.focus.grab = None;
+                    self.focus.drag = None;
+                    let cursor = self.cursor;
+                    self.move_cursor(cursor);
+                }
+            }
+            InputEvent::Scroll { x, y } => {
+                self.focus.scroll = (self.focus.scroll.0 + x, self.focus.scroll.1 + y);
+                self.events.lock().unwrap().push_back(PluginEvent::OnMouseScroll(x as f32, y as f32));
+
+                if let Some(id) = self.focus.pointer_target() {
+                    let _ = self.send_event(id, ClientEvent::MouseScroll { x, y });
+                }
+            }
+            InputEvent::Key { character, scancode, pressed } => {
+                if scancode == SCANCODE_SUPER {
+                    self.focus.modifier = pressed;
+                }
+
+                self.focus.keys.retain(|i| *i != scancode);
+                match pressed {
+                    true => self.focus.keys.push(scancode),
+                    false => self.focus.released.push(scancode),
+                }
+
+                self.events.lock().unwrap().push_back(match pressed {
+                    true => PluginEvent::OnKeyDown(scancode),
+                    false => PluginEvent::OnKeyUp(scancode),
+                });
+
+                if let Some(id) = self.focus.focused {
+                    let _ = self.send_event(id, ClientEvent::Key { scancode, character, pressed });
+                }
+            }
+        }
+    }
+
+    fn move_cursor(&mut self, pos: IntPoint) {
+        self.cursor = IntPoint::new(pos.x.clamp(0, (self.surface.width() - 1).max(0)),
+                                    pos.y.clamp(0, (self.surface.height() - 1).max(0)));
+
+        if let Some(drag) = self.focus.drag {
+            self.drag_frame(drag);
+        }
+
+        let hovered = self.hit_test(self.cursor);
+        if hovered != self.focus.hovered {
+            self.focus.hovered = hovered;
+
+            if self.focus.mode == FocusMode::FollowsMouse && self.focus.grab.is_none() {
+                self.focus_frame(hovered);
+            }
+        }
+
+        self.events.lock().unwrap().push_back(PluginEvent::OnMouseMove(self.cursor.x, self.cursor.y));
+
+        if let Some(id) = self.focus.pointer_target() {
+            if let Some(frame) = self.frames.get_mut(&id) {
+                let pos = self.cursor - frame.pos.to_vector();
+                frame.push_event(ClientEvent::MouseMove { x: pos.x, y: pos.y });
+            }
+        }
+    }
+
+    /// The topmost visible frame whose bounds contain `point`
+    pub fn hit_test(&self, point: IntPoint) -> Option<usize> {
+        self.stack.iter()
+            .rev()
+            .find(|id| self.frames.get(id).map_or(false, |frame| !frame.minimised && self.bounds(frame).contains(point)))
+    }
+
+    /// The area covered by a frame, including its decorations
+    pub fn bounds(&self, frame: &Frame) -> IntRect {
+        match (&self.decorations, frame.decorated) {
+            (Some(decorations), true) => decorations.outer_rect(&frame.rect()),
+            _ => frame.rect()
+        }
+    }
+
+    fn damage_frame(&mut self, id: usize) {
+        if let Some(rect) = self.frames.get(&id).map(|frame| self.bounds(frame)) {
+            self.damage(rect);
+        }
+    }
+
+    /// Which part of the frame's decorations `point` lies on, if the frame is decorated
+    pub fn decoration_hit(&self, id: usize, point: IntPoint) -> Option<Hit> {
+        let frame = self.frames.get(&id)?;
+
+        match (&self.decorations, frame.decorated) {
+            (Some(decorations), true) => decorations.hit_test(frame, point),
+            _ => None
+        }
+    }
+
+    fn press_decoration(&mut self, id: usize, hit: Hit, button: u8) {
+        if button != BUTTON_LEFT {
+            return;
+        }
+
+        let _ = match hit {
+            Hit::Button(Button::Close) => self.send_event(id, ClientEvent::CloseRequested),
+            Hit::Button(Button::Minimise) => self.minimise_frame(id),
+            Hit::Button(Button::Maximise) => self.maximise_frame(id),
+            Hit::Title => Ok(self.start_drag(id, DragKind::Move)),
+            Hit::Border(edges) => Ok(self.start_drag(id, DragKind::Resize(edges))),
+        };
+    }
+
+    /// Moves the frame with the left button, or resizes it from the nearest corner with the right button
+    fn press_modified(&mut self, id: usize, button: u8) {
+        let Some(frame) = self.frames.get(&id) else {
+            return;
+        };
+
+        match button {
+            BUTTON_LEFT => self.start_drag(id, DragKind::Move),
+            BUTTON_RIGHT => self.start_drag(id, DragKind::Resize(Edges::nearest(&frame.rect(), self.cursor))),
+            _ => {}
+        }
+    }
+
+    /// Makes the frame follow the pointer until all buttons are released
+    fn start_drag(&mut self, id: usize, kind: DragKind) {
+        let Some(frame) = self.frames.get(&id) else {
+            return;
+        };
+
+        if let DragKind::Resize(_) = kind {
+            if !frame.can_resize {
+                return;
+            }
+        }
+
+        self.focus.drag = Some(Drag { id, kind, start: self.cursor, rect: frame.rect() });
+    }
+
+    fn drag_frame(&mut self, drag: Drag) {
+        let target = drag.rect(self.cursor);
+
+        let _ = match drag.kind {
+            DragKind::Move => self.move_frame(drag.id, target.min),
+            DragKind::Resize(edges) => {
+                let Some(frame) = self.frames.get_mut(&drag.id) else {
+                    return;
+                };
+
+                // a resized frame is no longer maximised
+                frame.restore = None;
+
+                // the edges which aren't being dragged stay where they are
+                let size = target.size().clamp(frame.min_size, frame.max_size);
+                let pos = IntPoint::new(if edges.left { target.max.x - size.width } else { target.min.x },
+                                        if edges.top { target.max.y - size.height } else { target.min.y });
+
+                self.move_frame(drag.id, pos).and_then(|_| self.resize_frame(drag.id, size))
+            }
+        };
+    }
+
+    /// Moves keyboard focus, notifying both the previously and newly focused frames
+    pub fn focus_frame(&mut self, id: Option<usize>) {
+        if self.focus.focused == id {
+            return;
+        }
+
+        if let Some(previous) = self.focus.focused {
+            let _ = self.send_event(previous, ClientEvent::Focus(false));
+            // the title bar shows whether the frame is focused
+            self.damage_frame(previous);
+        }
+
+        self.focus.focused = id;
+
+        if let Some(id) = id {
+            let _ = self.send_event(id, ClientEvent::Focus(true));
+            self.damage_frame(id);
+        }
+    }
+
+    /// Marks a region of the surface as changed, so it is recomposited and synced on the next draw.
+    pub fn damage(&mut self, rect: IntRect) {
+        let bounds = IntRect::from_size(Size2D::new(self.surface.width(), self.surface.height()));
+
+        if let Some(rect) = rect.intersection(&bounds) {
+            self.damage.add(rect);
+        }
+    }
+
     pub fn draw(&mut self) {
-        self.surface.clear(SolidSource::from_unpremultiplied_argb(0xff, 0, 0, 0));
+        let fading: Vec<IntRect> = self.frames.values()
+            .filter(|i| i.is_fading())
+            .map(|i| i.rect())
+            .collect();
+        fading.into_iter().for_each(|i| self.damage(i));
+
+        if self.damage.is_empty() {
+            return;
+        }
+
+        let damage = self.damage.take();
+        let mut backdrops = Vec::new();
+
+        // what lies beneath a frame only shows in its backdrop if the frame's owner may see it
+        let stack = self.stack.to_vec();
+        let mut hidden = HashMap::new();
+        for (index, id) in stack.iter().enumerate() {
+            let Some(frame) = self.frames.get(id).filter(|i| i.buffers.has_backdrop()) else {
+                continue;
+            };
+
+            hidden.insert(*id, stack[..index].iter()
+                .filter_map(|i| self.frames.get(i))
+                .filter(|i| !i.minimised && !self.permitted(&frame.owner, i.id))
+                .map(|i| self.bounds(i))
+                .collect::<Vec<_>>());
+        }
+
+        for rect in damage.iter() {
+            self.surface.fill_rect(rect.min.x as f32,
+                                   rect.min.y as f32,
+                                   rect.width() as f32,
+                                   rect.height() as f32,
+                                   &Solid(solid_source(self.config.colors.background)),
+                                   &DrawOptions { blend_mode: BlendMode::Src, ..DrawOptions::default() });
+
+            for id in self.stack.iter() {
+                let Some(frame) = self.frames.get_mut(&id) else {
+                    continue;
+                };
 
-        self.frames.values_mut().for_each(|i| i.draw(&mut self.surface));
-        self.displays.iter_mut().for_each(|i| i.draw(&mut self.surface));
+                if frame.minimised {
+                    continue;
+                }
+
+                // at this point, the surface holds exactly what lies beneath the frame
+                let hidden = hidden.get(&id).map_or(&[][..], |i| i.as_slice());
+                if frame.capture_backdrop(&self.surface, rect, hidden, self.config.colors.background) && !backdrops.contains(&id) {
+                    backdrops.push(id);
+                }
+
+                if let (Some(decorations), true) = (&self.decorations, frame.decorated) {
+                    decorations.draw(frame, self.focus.focused == Some(id), &mut self.surface, rect);
+                }
+
+                frame.draw(&mut self.surface, rect);
+            }
+
+            self.overlays.iter().for_each(|i| i.draw(&mut self.surface, rect));
+        }
+
+        self.displays.iter_mut().for_each(|i| i.draw(&mut self.surface, &damage));
+
+        for id in backdrops {
+            let _ = self.send_event(id, ClientEvent::Backdrop);
+        }
     }
 
     pub fn get_layout(&self) -> Vec<IntRect> {
-        self.displays.iter().map(|i| IntRect::from_origin_and_size(i.pos, Size2D::new(i.surface.width(), i.surface.height()))).collect()
+        self.displays.iter().map(|i| i.rect()).collect()
     }
 
-    pub fn mk_frame(&mut self, options: FrameOptions) -> syscall::Result<&Frame> {
-        let id = self.frames.keys().max().unwrap_or(&0) + 1;
-        let frame = Frame::new(options, id);
+    /// Creates a frame. A child frame's position is relative to its parent.
+    pub fn mk_frame(&mut self, mut options: FrameOptions, owner: Owner) -> syscall::Result<&Frame> {
+        // clients' sizes are checked against their own limits when they open the frame
+        let limits = self.limits_of(&owner);
+        if let Owner::Plugin(_) = owner {
+            options.max_size = options.max_size.min(limits.size);
+        }
+        options.max_size = options.max_size.max(Size2D::zero());
+        options.min_size = options.min_size.max(Size2D::zero()).min(options.max_size);
+        options.size = options.size.clamp(options.min_size, options.max_size);
+
+        let (frames, bytes) = self.usage(&owner);
+        if frames >= limits.frames || bytes.saturating_add(Buffers::bytes_for(options.size, options.buffers, options.backdrop)) > limits.memory {
+            return Err(syscall::Error::new(syscall::EDQUOT));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        if let Some(parent) = options.parent.and_then(|i| self.frames.get(&i)) {
+            options.pos += parent.pos.to_vector();
+        }
+
+        let frame = Frame::new(options, id, owner, self.config.colors.frame);
 
         match frame {
-            Ok(frame) => self.frames.insert(id, frame),
+            Ok(frame) => {
+                self.damage(self.bounds(&frame));
+                self.stack.insert(id, frame.z_index);
+                self.frames.insert(id, frame)
+            },
             Err(err) => return Err(syscall::Error { errno: err }),
         };
 
+        if let Some(parent) = self.frames.get(&id).and_then(|frame| frame.parent) {
+            self.stack_children(parent);
+        }
+
         let Some(frame) = self.frames.get(&id) else {
             return Err(syscall::Error { errno: syscall::EINVAL });
         };
 
-        self.events.lock().unwrap().push_back(PluginEvent::OnFrameCreate(frame.get_messenger()));
+        let messenger = frame.get_messenger();
+        self.events.lock().unwrap().push_back(PluginEvent::OnFrameCreate(messenger.clone()));
+        self.events.lock().unwrap().push_back(PluginEvent::OnFrameEvent(FrameEvent::Created(messenger)));
+
+        self.frames.get(&id).ok_or(syscall::Error::new(syscall::EINVAL))
+    }
+
+    /// Presents what the client has drawn, returning the buffer it should draw into next
+    fn update_frame(&mut self, id: usize) -> syscall::Result<usize> {
+        let Some(frame) = self.frames.get_mut(&id) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        frame.last_update = Instant::now();
+        frame.acknowledge();
+        let back = frame.buffers.swap();
+        let rect = frame.rect();
+
+        self.events.lock().unwrap().push_back(PluginEvent::OnFrameUpdate(frame.get_messenger()));
+        self.damage(rect);
+
+        Ok(back)
+    }
+
+    /// Moves the frame, and its descendants along with it, to `pos` on the surface.
+    /// The frame's client is told its position relative to its parent, which its descendants' positions don't change.
+    pub fn move_frame(&mut self, id: usize, pos: IntPoint) -> syscall::Result<()> {
+        let Some(previous) = self.frames.get(&id).map(|frame| frame.pos) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        if pos == previous {
+            return Ok(());
+        }
+
+        for i in std::iter::once(id).chain(self.descendants(id)) {
+            self.damage_frame(i);
+            if let Some(frame) = self.frames.get_mut(&i) {
+                frame.pos += pos - previous;
+            }
+            self.damage_frame(i);
+        }
+
+        let relative = pos - self.origin(id).to_vector();
+        self.broadcast(FrameEvent::PosChanged(id, relative));
+
+        Ok(())
+    }
+
+    /// Reallocates the frame's buffer at `size`, clamped to its size limits, and tells the client about the new size
+    pub fn resize_frame(&mut self, id: usize, size: Size2D<i32, UnknownUnit>) -> syscall::Result<()> {
+        let Some(old) = self.frames.get(&id).map(|frame| self.bounds(frame)) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        if let Some(frame) = self.frames.get(&id) {
+            // unless the client never mapped them, the current buffers are kept until it switches over
+            let size = size.clamp(frame.min_size, frame.max_size);
+            let released = if frame.mapped { 0 } else { frame.buffers.bytes() };
+            let (_, bytes) = self.usage(&frame.owner);
+
+            if (bytes - released).saturating_add(Buffers::bytes_for(size, frame.buffers.mode(), frame.buffers.has_backdrop())) > self.limits_of(&frame.owner).memory {
+                return Err(syscall::Error::new(syscall::EDQUOT));
+            }
+        }
+
+        let fill = self.config.colors.frame;
+        let Some(frame) = self.frames.get_mut(&id) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+        let previous = frame.size();
+        let size = frame.resize(size, fill).map_err(syscall::Error::new)?;
+
+        if size == previous {
+            return Ok(());
+        }
+
+        self.damage(old);
+        self.damage_frame(id);
+
+        self.broadcast(FrameEvent::SizeChanged(id, size));
+
+        Ok(())
+    }
+
+    /// The number of frames the owner's user, or the plugin, has open, and the pixel memory they take up
+    pub fn usage(&self, owner: &Owner) -> (usize, usize) {
+        self.frames.values()
+            .filter(|frame| match (&frame.owner, owner) {
+                (Owner::Client { uid, .. }, Owner::Client { uid: other, .. }) => uid == other,
+                (Owner::Plugin(path), Owner::Plugin(other)) => path == other,
+                _ => false,
+            })
+            .fold((0, 0), |(frames, bytes), frame| (frames + 1, bytes + frame.bytes()))
+    }
+
+    /// The limits the owner's frames are subject to
+    pub fn limits_of(&self, owner: &Owner) -> Limits {
+        match owner {
+            Owner::Client { uid, .. } => self.config.limits_for(*uid),
+            Owner::Plugin(_) => self.config.plugin_frame_limits,
+        }
+    }
+
+    /// Hides the frame until it is raised again
+    pub fn minimise_frame(&mut self, id: usize) -> syscall::Result<()> {
+        let Some(frame) = self.frames.get_mut(&id) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        frame.minimised = true;
+        self.damage_frame(id);
+
+        if self.focus.focused == Some(id) {
+            self.focus_frame(None);
+        }
+
+        let cursor = self.cursor;
+        self.move_cursor(cursor);
 
-        Ok(frame)
+        Ok(())
     }
 
-    fn update_frame(&mut self, id: usize) -> syscall::Result<()> {
+    /// Fills the display the frame is on, or returns it to where it was before it was maximised
+    pub fn maximise_frame(&mut self, id: usize) -> syscall::Result<()> {
+        let Some(frame) = self.frames.get(&id) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        let target = match frame.restore {
+            Some(restore) => restore,
+            None => {
+                let Some(display) = self.displays.iter()
+                    .map(|i| i.rect())
+                    .find(|i| i.contains(frame.pos))
+                    .or_else(|| self.displays.first().map(|i| i.rect())) else {
+                    return Ok(());
+                };
+
+                // the decorations must fit on the display too
+                let outer = self.bounds(frame);
+                let inner = frame.rect();
+                IntRect::new(display.min + (inner.min - outer.min), display.max - (outer.max - inner.max))
+            }
+        };
+
+        let restore = match frame.restore {
+            Some(_) => None,
+            None => Some(frame.rect()),
+        };
+
+        self.move_frame(id, target.min)?;
+        self.resize_frame(id, target.size())?;
+
         if let Some(frame) = self.frames.get_mut(&id) {
-            frame.last_update = Instant::now();
-            frame.draw(&mut self.surface);
+            frame.restore = restore;
+        }
 
-            self.events.lock().unwrap().push_back(PluginEvent::OnFrameUpdate(frame.get_messenger()));
-        } else {
+        Ok(())
+    }
+
+    /// Queues an event to be read by the client owning the frame
+    pub fn send_event(&mut self, id: usize, event: ClientEvent) -> syscall::Result<()> {
+        let Some(frame) = self.frames.get_mut(&id) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        frame.push_event(event);
+
+        Ok(())
+    }
+
+    /// Moves the frame to the top of its `ZIndex` layer, restoring it if it was minimised.
+    /// A child frame is raised above its siblings, and its outermost ancestor to the top of the layer.
+    pub fn raise_frame(&mut self, id: usize) -> syscall::Result<()> {
+        let Some(frame) = self.frames.get_mut(&id) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        frame.minimised = false;
+
+        let root = self.root(id);
+        self.stack.raise(root);
+        self.stack.raise(id);
+        self.stack_children(root);
+        self.damage_tree(root);
+
+        Ok(())
+    }
+
+    /// Moves the frame's outermost ancestor, along with all its descendants, to the bottom of its `ZIndex` layer
+    pub fn lower_frame(&mut self, id: usize) -> syscall::Result<()> {
+        if !self.frames.contains_key(&id) {
             return Err(syscall::Error::new(syscall::ENOENT));
         }
 
+        let root = self.root(id);
+        self.stack.lower(root);
+        self.stack_children(root);
+        self.damage_tree(root);
+
         Ok(())
     }
 
+    /// The frame's children, from bottom to top
+    pub fn children(&self, id: usize) -> Vec<usize> {
+        self.stack.iter()
+            .filter(|i| self.frames.get(i).map_or(false, |frame| frame.parent == Some(id)))
+            .collect()
+    }
+
+    /// The frame's children, their children and so on
+    pub fn descendants(&self, id: usize) -> Vec<usize> {
+        self.children(id).into_iter()
+            .flat_map(|i| std::iter::once(i).chain(self.descendants(i)))
+            .collect()
+    }
+
+    /// The frame's outermost ancestor, or the frame itself if it has no parent
+    pub fn root(&self, id: usize) -> usize {
+        let mut root = id;
+        while let Some(parent) = self.frames.get(&root).and_then(|frame| frame.parent) {
+            root = parent;
+        }
+
+        root
+    }
+
+    /// What the frame's position is relative to: its parent's position, or the surface's origin
+    fn origin(&self, id: usize) -> IntPoint {
+        self.frames.get(&id)
+            .and_then(|frame| frame.parent)
+            .and_then(|parent| self.frames.get(&parent))
+            .map_or(IntPoint::origin(), |parent| parent.pos)
+    }
+
+    /// Stacks the frame's descendants directly above it, in their parent's layer and keeping their order.
+    /// Returns the topmost of them, or the frame itself if it has none.
+    fn stack_children(&mut self, id: usize) -> usize {
+        let mut top = id;
+        for child in self.children(id) {
+            self.stack.place_above(child, top);
+            top = self.stack_children(child);
+        }
+
+        top
+    }
+
+    fn damage_tree(&mut self, id: usize) {
+        for i in std::iter::once(id).chain(self.descendants(id)) {
+            self.damage_frame(i);
+        }
+    }
+
+    /// The frame ids from bottom to top
+    pub fn get_stack(&self) -> Vec<usize> {
+        self.stack.to_vec()
+    }
+
+    /// Destroys the frame and its descendants
     pub fn close_frame(&mut self, id: usize) -> syscall::Result<()> {
         if !self.frames.contains_key(&id) {
             return Err(syscall::Error {
                 errno: syscall::ENOENT,
             });
         }
+
+        for child in self.children(id) {
+            self.close_frame(child)?;
+        }
+        self.damage_frame(id);
+
+        // queued while the frame exists, so a client whose handle is still open can read it
+        self.broadcast(FrameEvent::Destroyed(id));
+
         if let Some(frame) = self.frames.remove(&id) {
+            self.stack.remove(id);
+            self.focus.remove(id);
             self.events.lock().unwrap().push_back(PluginEvent::OnFrameDestroy(frame.get_messenger()));
+            if self.handles.contains_key(&id) {
+                self.orphaned.insert(id, frame.events);
+            }
         }
 
         Ok(())
     }
 
+    /// Whether `caller` created frame `id`, or may act as though it did
+    pub fn owns(&self, caller: &Owner, id: usize) -> bool {
+        let Some(frame) = self.frames.get(&id) else {
+            return false;
+        };
+
+        match caller {
+            Owner::Client { .. } => frame.owner == *caller,
+            Owner::Plugin(path) => frame.owner == *caller || self.plugin_has(path, Capability::Frames),
+        }
+    }
+
+    pub fn plugin_has(&self, path: &str, capability: Capability) -> bool {
+        self.plugin_capabilities.get(path).map_or(false, |i| i.contains(&capability))
+    }
+
+    /// Whether `caller` may change frame `id`, parent frames to it and see it in their backdrops
+    pub fn permitted(&self, caller: &Owner, id: usize) -> bool {
+        self.owns(caller, id) || match (caller.uid(), self.frames.get(&id)) {
+            (Some(uid), Some(frame)) => frame.grants.contains(&uid),
+            _ => false,
+        }
+    }
+
+    /// Fails with `ENOENT` if the frame doesn't exist, or `EPERM` if `caller` isn't permitted to change it
+    pub fn authorise(&self, caller: &Owner, id: usize) -> syscall::Result<()> {
+        if !self.frames.contains_key(&id) {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        }
+
+        match self.permitted(caller, id) {
+            true => Ok(()),
+            false => Err(syscall::Error::new(syscall::EPERM)),
+        }
+    }
+
+    /// Carries out a change to a frame on behalf of `caller`, which is either the frame's client or a plugin.
+    /// Fails with `EPERM` if the caller isn't permitted to change the frame, or to parent it to the requested frame.
+    /// Plugins and the frame's client are told about the change. Returns the id of the frame concerned.
+    pub fn apply(&mut self, caller: &Owner, request: FrameRequest) -> syscall::Result<usize> {
+        // everything but `Create` concerns an existing frame
+        let id = match request.id() {
+            Some(id) => self.authorise(caller, id).map(|_| id)?,
+            None => 0,
+        };
+
+        let parent = match &request {
+            FrameRequest::Create(options) => options.parent,
+            FrameRequest::SetParent(_, parent) => Some(*parent),
+            _ => None,
+        };
+        if let Some(parent) = parent {
+            self.authorise(caller, parent)?;
+        }
+
+        match request {
+            FrameRequest::Create(options) => return self.mk_frame(options, caller.clone()).map(|frame| frame.id),
+            FrameRequest::Destroy(id) => self.close_frame(id)?,
+            FrameRequest::SetSize(id, size) => self.resize_frame(id, size)?,
+            FrameRequest::SetPos(id, pos) => self.move_frame(id, pos + self.origin(id).to_vector())?,
+            FrameRequest::SetTitle(id, title) => self.set_property(id, FrameEvent::TitleChanged(id, title.clone()), |frame| frame.title = title),
+            FrameRequest::SetParent(id, parent) => {
+                // a frame can't end up as its own ancestor
+                let mut ancestor = Some(parent);
+                while let Some(i) = ancestor {
+                    if i == id {
+                        return Err(syscall::Error::new(syscall::EINVAL));
+                    }
+                    ancestor = self.frames.get(&i).ok_or(syscall::Error::new(syscall::ENOENT))?.parent;
+                }
+
+                // the frame stays where it is on the surface
+                self.set_property(id, FrameEvent::ParentChanged(id, parent), |frame| frame.parent = Some(parent));
+                self.stack_children(parent);
+                self.damage_tree(parent);
+            }
+            // children stay in their parent's layer
+            FrameRequest::SetZIndex(id, _) if self.frames.get(&id).map_or(false, |frame| frame.parent.is_some()) => return Err(syscall::Error::new(syscall::EINVAL)),
+            FrameRequest::SetZIndex(id, z_index) => {
+                self.stack.insert(id, z_index);
+                self.stack_children(id);
+                self.damage_tree(id);
+                self.set_property(id, FrameEvent::ZIndexChanged(id, z_index), |frame| frame.z_index = z_index);
+            }
+            FrameRequest::SetCanMinimise(id, value) => self.set_property(id, FrameEvent::CanMinimiseChanged(id, value), |frame| frame.can_minimise = value),
+            FrameRequest::SetCanResize(id, value) => self.set_property(id, FrameEvent::CanResizeChanged(id, value), |frame| frame.can_resize = value),
+            FrameRequest::SetCanClose(id, value) => self.set_property(id, FrameEvent::CanCloseChanged(id, value), |frame| frame.can_close = value),
+            FrameRequest::SetTransparent(id, value) => self.set_property(id, FrameEvent::TransparentChanged(id, value), |frame| frame.transparent = value),
+            FrameRequest::SetOpacity(_, value) if !value.is_finite() => return Err(syscall::Error::new(syscall::EINVAL)),
+            FrameRequest::SetOpacity(id, value) => {
+                let opacity = value.clamp(0., 1.);
+                self.set_property(id, FrameEvent::OpacityChanged(id, opacity), |frame| frame.opacity = opacity);
+            }
+            // those given a grant can't pass it on
+            FrameRequest::Grant(id, _) | FrameRequest::Revoke(id, _) if !self.owns(caller, id) => return Err(syscall::Error::new(syscall::EPERM)),
+            FrameRequest::Grant(id, uid) => if let Some(frame) = self.frames.get_mut(&id) {
+                if !frame.grants.contains(&uid) {
+                    frame.grants.push(uid);
+                }
+            },
+            FrameRequest::Revoke(id, uid) => {
+                if let Some(frame) = self.frames.get_mut(&id) {
+                    frame.grants.retain(|i| *i != uid);
+                }
+                // backdrops which showed the frame are recaptured without it
+                self.damage_frame(id);
+            }
+        };
+
+        Ok(id)
+    }
+
+    fn set_property(&mut self, id: usize, event: FrameEvent, update: impl FnOnce(&mut Frame)) {
+        if let Some(frame) = self.frames.get_mut(&id) {
+            update(frame);
+        }
+
+        // the decorations show most properties
+        self.damage_frame(id);
+        self.broadcast(event);
+    }
+
+    /// Tells plugins and the frame's client about a change to a frame
+    fn broadcast(&mut self, event: FrameEvent) {
+        if let Some(client) = ClientEvent::from_frame_event(&event) {
+            let _ = self.send_event(event.id(), client);
+        }
+
+        self.events.lock().unwrap().push_back(PluginEvent::OnFrameEvent(event));
+    }
+
     pub fn get_frame_by_id(&self, id: usize) -> Option<&Frame> {
         self.frames.get(&id)
     }
 
-    pub fn paint_buffer(&mut self, buffer: Vec<u32>, rect: Box2D<i32, UnknownUnit>) {
-        todo!()
+    /// Paints an ARGB buffer above all frames on behalf of the plugin at `owner`.
+    /// Painting over the exact rect of one of the plugin's overlays replaces it.
+    pub fn paint_buffer(&mut self, owner: &str, buffer: Vec<u32>, rect: Box2D<i32, UnknownUnit>) -> syscall::Result<()> {
+        let overlay = match Overlay::new(buffer, rect, owner) {
+            Ok(overlay) => overlay,
+            Err(err) => {
+                eprintln!("Unable to paint buffer: {}", err);
+                return Err(syscall::Error::new(syscall::EINVAL));
+            }
+        };
+
+        match self.overlays.iter_mut().find(|i| i.rect == rect && i.owner == owner) {
+            Some(existing) => *existing = overlay,
+            None => self.overlays.push(overlay),
+        };
+
+        self.damage(rect);
+
+        Ok(())
+    }
+
+    /// Removes the overlay the plugin at `owner` painted at exactly `rect`
+    pub fn clear_buffer(&mut self, owner: &str, rect: Box2D<i32, UnknownUnit>) -> syscall::Result<()> {
+        let Some(index) = self.overlays.iter().position(|i| i.rect == rect && i.owner == owner) else {
+            return Err(syscall::Error::new(syscall::ENOENT));
+        };
+
+        self.overlays.remove(index);
+        self.damage(rect);
+
+        Ok(())
     }
+
+    /// Closes the frames and clears the overlays belonging to the plugin at `path`, once it's unloaded
+    pub fn remove_plugin(&mut self, path: &str) {
+        let owner = Owner::Plugin(path.to_owned());
+        let frames: Vec<usize> = self.frames.values()
+            .filter(|frame| frame.owner == owner)
+            .map(|frame| frame.id)
+            .collect();
+
+        // closing a frame closes its children, which may be among these
+        for id in frames {
+            if self.frames.contains_key(&id) {
+                let _ = self.close_frame(id);
+            }
+        }
+
+        let removed: Vec<IntRect> = self.overlays.iter()
+            .filter(|i| i.owner == path)
+            .map(|i| i.rect)
+            .collect();
+        self.overlays.retain(|i| i.owner != path);
+        removed.into_iter().for_each(|rect| self.damage(rect));
+
+        self.plugin_capabilities.remove(path);
+    }
+}
+
+/// The size of the surface spanning every display
+fn surface_size(displays: &[Display]) -> Size2D<i32, UnknownUnit> {
+    let mut min = (0, 0);
+    let mut max = (0, 0);
+
+    displays.iter().for_each(|i| {
+        min = (i.pos.x.min(min.0), i.pos.y.min(min.1));
+        max = ((i.pos.x + i.size().width).max(max.0), (i.pos.y + i.size().height).max(max.1));
+    });
+
+    Size2D::new(max.0 - min.0, max.1 - min.1)
 }
 
-impl<'a, 'b> SchemeMut for Compositor<'a, 'b> {
+fn load_decorations(config: &Config) -> Option<Decorations> {
+    config.decorations.clone().and_then(|decorations| match Decorations::new(decorations) {
+        Ok(decorations) => Some(decorations),
+        Err(err) => {
+            eprintln!("Disabling decorations: {}", err);
+            None
+        }
+    })
+}
+
+/// Creates the scheme, or joins it if it already exists
+fn open_scheme() -> Result<File, String> {
+    syscall::open(SCHEME_NAME, syscall::O_CREAT | syscall::O_RDWR | syscall::O_CLOEXEC | O_NONBLOCK)
+        .or_else(|_| syscall::open(SCHEME_NAME, syscall::O_RDWR | syscall::O_CLOEXEC | O_NONBLOCK))
+        .map(|socket| unsafe { File::from_raw_fd(socket as RawFd) })
+        .map_err(|err| format!("Unable to open scheme {}: {}", SCHEME_NAME, err))
+}
+
+fn open_input(config: &Config) -> Option<Input> {
+    config.input.as_ref().and_then(|path| match Input::open(path) {
+        Ok(input) => Some(input),
+        Err(err) => {
+            eprintln!("{}", err);
+            None
+        }
+    })
+}
+
+impl SchemeMut for Compositor {
+    /// Opens a new frame, subject to the limits configured for `uid`.
+    /// Fails with `ENOMEM` if the frame is larger than allowed, or `EDQUOT` if the user has too many frames or too much pixel memory.
     fn open(&mut self, path: &str, flags: usize, uid: u32, gid: u32) -> syscall::Result<usize> {
-        let options = match FrameOptions::from_string(path) {
+        let mut options = match FrameOptions::from_string_with(path, self.config.frame.clone()) {
             Ok(options) => options,
             Err(err) => return Err(syscall::Error {
                 errno: syscall::EINVAL,
             }),
         };
 
-        match self.mk_frame(options) {
-            Ok(frame) => Ok(frame.id),
-            Err(err) => Err(err)
+        let limits = self.config.limits_for(uid);
+        if options.size.width > limits.size.width || options.size.height > limits.size.height {
+            return Err(syscall::Error::new(syscall::ENOMEM));
         }
+
+        options.max_size = options.max_size.min(limits.size);
+        options.min_size = options.min_size.min(options.max_size);
+
+        let owner = Owner::Client { uid, gid, pid: self.caller_pid };
+        let id = self.apply(&owner, FrameRequest::Create(options))?;
+        self.handles.insert(id, owner);
+
+        Ok(id)
     }
 
     fn fmap_old(&mut self, id: usize, map: &syscall::OldMap) -> syscall::Result<usize> {
@@ -183,12 +1095,14 @@ impl<'a, 'b> SchemeMut for Compositor<'a, 'b> {
         })
     }
 
+    /// Maps the frame's buffers. Buffer `i` starts at offset `i * stride`, where the stride is the size of one buffer
+    /// rounded up to a whole page, so buffers can be mapped together or one at a time. A backdrop follows the last buffer.
     fn fmap(&mut self, id: usize, map: &syscall::Map) -> syscall::Result<usize> {
         if let Some(frame) = self.frames.get_mut(&id) {
-            let map_pages = (map.offset + map.size + (syscall::PAGE_SIZE - 1)) / syscall::PAGE_SIZE;
-            let (data_addr, len) = frame.mut_ptr();
+            let (data_addr, len) = frame.buffers.mut_ptr();
 
-            if map_pages * syscall::PAGE_SIZE >= len * std::mem::size_of::<u32>() {
+            if map.offset % syscall::PAGE_SIZE == 0 && map.offset.checked_add(map.size).map_or(false, |end| end <= len) {
+                frame.mapped = true;
                 Ok((data_addr as usize) + map.offset)
             } else {
                 Err(syscall::Error::new(syscall::EINVAL))
@@ -198,11 +1112,107 @@ impl<'a, 'b> SchemeMut for Compositor<'a, 'b> {
         }
     }
 
+    /// Reads as many whole `RawEvent`s as fit in `buf`, failing with `EAGAIN` if none are queued.
+    fn read(&mut self, id: usize, buf: &mut [u8]) -> syscall::Result<usize> {
+        let events = match self.frames.get_mut(&id) {
+            Some(frame) => &mut frame.events,
+            None => match self.orphaned.get_mut(&id) {
+                Some(events) => events,
+                None => return Err(syscall::Error::new(syscall::ENOENT)),
+            }
+        };
+
+        let size = std::mem::size_of::<RawEvent>();
+        if buf.len() < size {
+            return Err(syscall::Error::new(syscall::EINVAL));
+        }
+
+        if events.is_empty() {
+            return Err(syscall::Error::new(syscall::EAGAIN));
+        }
+
+        let mut count = 0;
+        while count + size <= buf.len() {
+            let Some(event) = events.pop_front() else {
+                break;
+            };
+
+            buf[count..count + size].copy_from_slice(RawEvent::from(event).as_bytes());
+            count += size;
+        }
+
+        Ok(count)
+    }
+
+    /// Accepts control commands in the grammar of `FrameRequest::from_string`, eg. `title=Editor&pos=40,40`.
+    /// Nothing is changed unless every command parses. After `size=`, the client is sent an `EVENT_RESIZE` with the
+    /// size the frame ended up with, and must `fmap` again. The old buffer stays valid until the next `fsync` after that.
+    fn write(&mut self, id: usize, buf: &[u8]) -> syscall::Result<usize> {
+        // whoever holds the handle acts as the client which opened it
+        let Some(caller) = self.handles.get(&id).cloned() else {
+            return Err(syscall::Error::new(syscall::EBADF));
+        };
+
+        let Ok(commands) = std::str::from_utf8(buf) else {
+            return Err(syscall::Error::new(syscall::EINVAL));
+        };
+
+        let requests = match FrameRequest::from_string(id, commands) {
+            Ok(requests) => requests,
+            Err(err) => {
+                eprintln!("Frame {}: {}", id, err);
+                return Err(syscall::Error::new(syscall::EINVAL));
+            }
+        };
+
+        for request in requests {
+            self.apply(&caller, request)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    /// Swaps the frame's buffers. Returns the index of the buffer to draw into next.
     fn fsync(&mut self, id: usize) -> syscall::Result<usize> {
-        self.update_frame(id).map(|i| 0)
+        self.update_frame(id)
     }
 
     fn close(&mut self, id: usize) -> syscall::Result<usize> {
-        self.close_frame(id).map(|i| 0)
+        self.handles.remove(&id);
+
+        // the frame may already have been destroyed along with its parent
+        if self.orphaned.remove(&id).is_some() {
+            return Ok(0);
+        }
+
+        self.close_frame(id).map(|_| 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use euclid::Point2D;
+
+    use crate::display::MEMORY_PREFIX;
+
+    use super::*;
+
+    #[test]
+    fn draws_frames_on_a_memory_display() {
+        let config = Config {
+            displays: vec![(format!("{}64x48", MEMORY_PREFIX), IntPoint::new(0, 0))],
+            input: None,
+            ..Config::default()
+        };
+        let (background, fill) = (config.colors.background, config.colors.frame);
+
+        let (mut comp, _) = Compositor::with_scheme(config, None).unwrap();
+        let options = FrameOptions { pos: Point2D::new(8, 8), size: Size2D::new(16, 16), ..FrameOptions::default() };
+        comp.apply(&Owner::Plugin("test.lua".to_owned()), FrameRequest::Create(options)).unwrap();
+        comp.draw();
+
+        let pixels = comp.displays[0].surface();
+        assert_eq!(pixels[12 * 64 + 12], fill);
+        assert_eq!(pixels[40 * 64 + 40], background);
     }
 }
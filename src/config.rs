@@ -1,14 +1,452 @@
-use raqote::IntPoint;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use euclid::{Size2D, UnknownUnit};
+use raqote::{IntPoint, SolidSource};
+use rlua::{FromLua, HookTriggers, Lua, StdLib, Table, Value};
+
+use crate::focus::FocusMode;
+use crate::frame::FrameOptions;
+
+/// The environment variable which may point at a config file
+pub const CONFIG_ENV: &'static str = "COMP_CONFIG";
+/// Searched in order if neither `--config` nor `COMP_CONFIG` are given. `~` is the user's home directory.
+pub const CONFIG_PATHS: [&'static str; 2] = ["~/.config/comp/config.lua", "/etc/comp/config.lua"];
+
+#[derive(Debug, Clone)]
+pub struct Colors {
+    pub background: u32,
+    pub frame: u32,
+}
+
+/// Converts a configured colour, given as unpremultiplied `0xAARRGGBB`, to something raqote can draw with
+pub fn solid_source(argb: u32) -> SolidSource {
+    SolidSource::from_unpremultiplied_argb((argb >> 24) as u8, (argb >> 16) as u8, (argb >> 8) as u8, argb as u8)
+}
+
+#[derive(Debug, Clone)]
+pub struct DecorationColors {
+    pub title: u32,
+    pub focused_title: u32,
+    pub text: u32,
+    pub border: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DecorationConfig {
+    /// A TrueType font used for frame titles
+    pub font: PathBuf,
+    pub font_size: f32,
+    pub title_height: i32,
+    pub border: i32,
+    pub colors: DecorationColors,
+}
+
+impl Default for DecorationConfig {
+    fn default() -> Self {
+        Self {
+            font: PathBuf::from("/ui/fonts/Sans/Fira/Regular.ttf"),
+            font_size: 14.,
+            title_height: 24,
+            border: 1,
+            colors: DecorationColors {
+                title: 0xff303030,
+                focused_title: 0xff3c5a8c,
+                text: 0xffffffff,
+                border: 0xff202020,
+            },
+        }
+    }
+}
+
+/// Caps on what a single user, or a single plugin, may allocate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// How many frames a user or plugin may have open at once
+    pub frames: usize,
+    /// The pixel memory all of a user's or plugin's frames may take up, in bytes
+    pub memory: usize,
+    /// The largest any one frame may be
+    pub size: Size2D<i32, UnknownUnit>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            frames: 256,
+            memory: 512 * 1024 * 1024,
+            size: Size2D::new(16384, 16384),
+        }
+    }
+}
+
+/// Rights a plugin only has if the config grants them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Change any frame, rather than only those the plugin created
+    Frames,
+    /// The whole Lua standard library, including `io`, `os` and `package`
+    Stdlib,
+    /// Receive mouse and keyboard events, and query the mouse and keys
+    Input,
+    /// Paint overlays above the frames
+    Overlays,
+}
+
+impl Capability {
+    pub fn from_str(capability: &str) -> Option<Capability> {
+        match capability {
+            "frames" => Some(Capability::Frames),
+            "stdlib" => Some(Capability::Stdlib),
+            "input" => Some(Capability::Input),
+            "overlays" => Some(Capability::Overlays),
+            _ => None
+        }
+    }
+}
+
+/// What each plugin may use. A plugin exceeding these is disabled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PluginLimits {
+    /// The memory the plugin's Lua state may allocate, in bytes
+    pub memory: usize,
+    /// How many Lua instructions the plugin may run each time it's called into, eg. to handle an event
+    pub instructions: u64,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        Self {
+            memory: 64 * 1024 * 1024,
+            instructions: 10_000_000,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
+    /// The file the config was read from, if any
+    pub path: Option<PathBuf>,
+    /// Either a `display:` scheme path or `memory:WIDTHxHEIGHT` for a headless display
     pub displays: Vec<(String, IntPoint)>,
-    pub plugins: Vec<String>
+    pub plugins: Vec<String>,
+    /// The capabilities granted to each plugin, by path. Plugins with a manifest only get those it asks for too.
+    pub capabilities: HashMap<String, Vec<Capability>>,
+    pub plugin_limits: PluginLimits,
+    /// Reload plugins when their files change, for plugin development
+    pub watch_plugins: bool,
+    /// The scheme input events are read from. Without one, the compositor runs without input.
+    pub input: Option<String>,
+    pub focus_mode: FocusMode,
+    /// The options new frames start with, before the client's own options are applied
+    pub frame: FrameOptions,
+    pub colors: Colors,
+    pub max_fps: u32,
+    /// Server-side decorations are only drawn if configured
+    pub decorations: Option<DecorationConfig>,
+    pub limits: Limits,
+    /// Limits for particular uids, replacing `limits`
+    pub user_limits: HashMap<u32, Limits>,
+    /// Limits for each plugin's frames, replacing `limits`
+    pub plugin_frame_limits: Limits,
 }
 
+impl Config {
+    /// The minimum time between two composites
+    pub fn frame_time(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000 / self.max_fps.max(1) as u64)
+    }
+
+    pub fn limits_for(&self, uid: u32) -> Limits {
+        self.user_limits.get(&uid).copied().unwrap_or(self.limits)
+    }
+
+    pub fn has_capability(&self, plugin: &str, capability: Capability) -> bool {
+        self.capabilities.get(plugin).map_or(false, |i| i.contains(&capability))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: None,
+            displays: Vec::new(),
+            plugins: Vec::new(),
+            capabilities: HashMap::new(),
+            plugin_limits: PluginLimits::default(),
+            watch_plugins: false,
+            input: Some("input:consumer".to_owned()),
+            focus_mode: FocusMode::ClickToFocus,
+            frame: FrameOptions::default(),
+            colors: Colors {
+                background: 0xff000000,
+                frame: 0xffaaaaaa,
+            },
+            max_fps: 60,
+            decorations: None,
+            limits: Limits::default(),
+            user_limits: HashMap::new(),
+            plugin_frame_limits: Limits::default(),
+        }
+    }
+}
+
+/// Loads the config from `--config <path>`, `$COMP_CONFIG` or the first of `CONFIG_PATHS` which exists.
+/// If none are found, the defaults are used, which have no displays or plugins.
 pub(crate) fn load() -> Result<Config, String> {
-    Ok(Config {
-        displays: vec![("display:3/activate".to_owned(), IntPoint::new(0, 0))],
-        plugins: vec!["/home/user/plugins/example2.lua".to_owned()]
+    match locate(std::env::args().skip(1))? {
+        Some(path) => load_from(&path),
+        None => {
+            eprintln!("No config found at {}, or in ${}. Using the defaults", CONFIG_PATHS.join(" or "), CONFIG_ENV);
+            Ok(Config::default())
+        }
+    }
+}
+
+fn locate(mut args: impl Iterator<Item=String>) -> Result<Option<PathBuf>, String> {
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return match args.next() {
+                Some(path) => Ok(Some(PathBuf::from(path))),
+                None => Err("--config requires a path".to_owned())
+            };
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return Ok(Some(PathBuf::from(path)));
+        }
+    }
+
+    if let Ok(path) = std::env::var(CONFIG_ENV) {
+        return Ok(Some(PathBuf::from(path)));
+    }
+
+    Ok(CONFIG_PATHS.iter()
+        .filter_map(|path| match path.strip_prefix("~/") {
+            Some(path) => std::env::var("HOME").ok().map(|home| Path::new(&home).join(path)),
+            None => Some(PathBuf::from(path))
+        })
+        .find(|path| path.is_file()))
+}
+
+pub fn load_from(path: &Path) -> Result<Config, String> {
+    let source = fs::read_to_string(path)
+        .map_err(|err| format!("Unable to read config {}: {}", path.display(), err))?;
+
+    parse(&source, &path.display().to_string())
+        .map(|config| Config { path: Some(path.to_owned()), ..config })
+}
+
+/// Evaluates a config file. The file is a Lua chunk which assigns the settings as globals:
+///
+/// ```lua
+/// displays = { { path = "display:3/activate", x = 0, y = 0 } }
+/// plugins = { "/home/user/plugins/example2.lua" }
+/// capabilities = { ["/home/user/plugins/example2.lua"] = { "frames", "stdlib", "input", "overlays" } }
+/// plugin_limits = { memory = 64 * 1024 * 1024, instructions = 10000000 }
+/// watch_plugins = false        -- reload plugins when their files change
+/// input = "input:consumer"     -- or false to run without input
+/// focus = "click"              -- or "mouse"
+/// frame = { size = { width = 640, height = 480 }, can_close = true }
+/// colors = { background = 0xff000000, frame = 0xffaaaaaa }
+/// max_fps = 60
+/// decorations = {
+///     font = "/ui/fonts/Sans/Fira/Regular.ttf",
+///     font_size = 14, title_height = 24, border = 1,
+///     colors = { title = 0xff303030, focused_title = 0xff3c5a8c, text = 0xffffffff, border = 0xff202020 },
+/// }
+/// limits = {
+///     frames = 256, memory = 512 * 1024 * 1024, size = { width = 16384, height = 16384 },
+///     users = { [1000] = { frames = 1024 } },  -- unset limits fall back to the ones above
+///     plugins = { frames = 32 },               -- for each plugin
+/// }
+/// ```
+///
+/// Errors are reported as `file:line: message`, using the line the offending setting was assigned on.
+pub fn parse(source: &str, name: &str) -> Result<Config, String> {
+    let lua = Lua::new_with(StdLib::BASE | StdLib::STRING | StdLib::TABLE | StdLib::MATH);
+
+    let line = Arc::new(AtomicI32::new(0));
+    let lines = Arc::new(Mutex::new(HashMap::<String, i32>::new()));
+
+    let current = Arc::clone(&line);
+    lua.set_hook(HookTriggers { every_line: true, ..HookTriggers::default() }, move |_ctx, debug| {
+        current.store(debug.curr_line(), Ordering::Relaxed);
+        Ok(())
+    });
+
+    let result = lua.context(|ctx| -> Result<Config, String> {
+        // record the line each global is assigned on, so conversion errors can point at it
+        let assigned = Arc::clone(&lines);
+        let current = Arc::clone(&line);
+        let meta = ctx.create_table().map_err(|err| err.to_string())?;
+        meta.set("__newindex", ctx.create_function(move |_ctx, (table, key, value): (Table, String, Value)| {
+            assigned.lock().unwrap().insert(key.clone(), current.load(Ordering::Relaxed));
+            table.raw_set(key, value)
+        }).map_err(|err| err.to_string())?).map_err(|err| err.to_string())?;
+        ctx.globals().set_metatable(Some(meta));
+
+        ctx.load(source)
+            .set_name(&format!("={}", name))
+            .and_then(|chunk| chunk.exec())
+            .map_err(|err| match err {
+                rlua::Error::SyntaxError { message, .. } => message,
+                rlua::Error::RuntimeError(message) => message.lines().next().unwrap_or_default().to_owned(),
+                err => format!("{}: {}", name, err)
+            })?;
+
+        let globals = ctx.globals();
+        let error = |key: &str, message: String| {
+            let line = lines.lock().unwrap().get(key).copied().unwrap_or(0);
+            format!("{}:{}: {}: {}", name, line, key, message)
+        };
+
+        let mut config = Config::default();
+
+        if let Some(displays) = get::<Vec<Table>>(&globals, "displays").map_err(|err| error("displays", err))? {
+            config.displays = displays.into_iter()
+                .map(|display| -> Result<(String, IntPoint), String> {
+                    let path = display.get::<_, String>("path")
+                        .map_err(|_| "each display needs a `path`".to_owned())?;
+                    let x = display.get::<_, Option<i32>>("x").map_err(|err| err.to_string())?.unwrap_or(0);
+                    let y = display.get::<_, Option<i32>>("y").map_err(|err| err.to_string())?.unwrap_or(0);
+                    Ok((path, IntPoint::new(x, y)))
+                })
+                .collect::<Result<_, _>>()
+                .map_err(|err| error("displays", err))?;
+        }
+
+        if let Some(plugins) = get::<Vec<String>>(&globals, "plugins").map_err(|err| error("plugins", err))? {
+            config.plugins = plugins;
+        }
+
+        if let Some(watch) = get::<bool>(&globals, "watch_plugins").map_err(|err| error("watch_plugins", err))? {
+            config.watch_plugins = watch;
+        }
+
+        if let Some(limits) = get::<Table>(&globals, "plugin_limits").map_err(|err| error("plugin_limits", err))? {
+            let defaults = config.plugin_limits;
+            config.plugin_limits = PluginLimits {
+                memory: limits.get::<_, Option<usize>>("memory").map_err(|err| error("plugin_limits", err.to_string()))?.unwrap_or(defaults.memory),
+                instructions: limits.get::<_, Option<u64>>("instructions").map_err(|err| error("plugin_limits", err.to_string()))?.unwrap_or(defaults.instructions),
+            };
+        }
+
+        if let Some(capabilities) = get::<Table>(&globals, "capabilities").map_err(|err| error("capabilities", err))? {
+            for pair in capabilities.pairs::<String, Vec<String>>() {
+                let (plugin, granted) = pair.map_err(|err| error("capabilities", err.to_string()))?;
+                let granted = granted.iter()
+                    .map(|i| Capability::from_str(i).ok_or_else(|| error("capabilities", format!("unknown capability '{}'", i))))
+                    .collect::<Result<_, _>>()?;
+                config.capabilities.insert(plugin, granted);
+            }
+        }
+
+        match globals.get::<_, Value>("input") {
+            Ok(Value::Nil) => {}
+            Ok(Value::Boolean(false)) => config.input = None,
+            Ok(Value::String(input)) => config.input = Some(input.to_str().map_err(|err| error("input", err.to_string()))?.to_owned()),
+            _ => return Err(error("input", "expected a path or false".to_owned()))
+        }
+
+        if let Some(focus) = get::<String>(&globals, "focus").map_err(|err| error("focus", err))? {
+            config.focus_mode = FocusMode::from_str(&focus)
+                .ok_or_else(|| error("focus", format!("unknown focus mode '{}', expected 'click' or 'mouse'", focus)))?;
+        }
+
+        if let Some(frame) = get::<FrameOptions>(&globals, "frame").map_err(|err| error("frame", err))? {
+            config.frame = frame;
+        }
+
+        if let Some(colors) = get::<Table>(&globals, "colors").map_err(|err| error("colors", err))? {
+            if let Some(background) = colors.get::<_, Option<u32>>("background").map_err(|err| error("colors", err.to_string()))? {
+                config.colors.background = background;
+            }
+            if let Some(frame) = colors.get::<_, Option<u32>>("frame").map_err(|err| error("colors", err.to_string()))? {
+                config.colors.frame = frame;
+            }
+        }
+
+        if let Some(max_fps) = get::<u32>(&globals, "max_fps").map_err(|err| error("max_fps", err))? {
+            if max_fps == 0 {
+                return Err(error("max_fps", "must be greater than 0".to_owned()));
+            }
+            config.max_fps = max_fps;
+        }
+
+        if let Some(decorations) = get::<Table>(&globals, "decorations").map_err(|err| error("decorations", err))? {
+            config.decorations = Some(parse_decorations(decorations).map_err(|err| error("decorations", err.to_string()))?);
+        }
+
+        if let Some(limits) = get::<Table>(&globals, "limits").map_err(|err| error("limits", err))? {
+            config.limits = parse_limits(&limits, config.limits).map_err(|err| error("limits", err.to_string()))?;
+
+            if let Some(users) = limits.get::<_, Option<Table>>("users").map_err(|err| error("limits", err.to_string()))? {
+                for pair in users.pairs::<u32, Table>() {
+                    let (uid, user) = pair.map_err(|err| error("limits", err.to_string()))?;
+                    config.user_limits.insert(uid, parse_limits(&user, config.limits).map_err(|err| error("limits", err.to_string()))?);
+                }
+            }
+
+            config.plugin_frame_limits = match limits.get::<_, Option<Table>>("plugins").map_err(|err| error("limits", err.to_string()))? {
+                Some(plugins) => parse_limits(&plugins, config.limits).map_err(|err| error("limits", err.to_string()))?,
+                None => config.limits,
+            };
+        }
+
+        Ok(config)
+    });
+
+    lua.remove_hook();
+
+    result
+}
+
+fn parse_decorations(table: Table) -> rlua::Result<DecorationConfig> {
+    let mut decorations = DecorationConfig::default();
+
+    if let Some(font) = table.get::<_, Option<String>>("font")? {
+        decorations.font = PathBuf::from(font);
+    }
+    decorations.font_size = table.get::<_, Option<f32>>("font_size")?.unwrap_or(decorations.font_size);
+    decorations.title_height = table.get::<_, Option<i32>>("title_height")?.unwrap_or(decorations.title_height).max(0);
+    decorations.border = table.get::<_, Option<i32>>("border")?.unwrap_or(decorations.border).max(0);
+
+    if let Some(colors) = table.get::<_, Option<Table>>("colors")? {
+        let defaults = &decorations.colors;
+        decorations.colors = DecorationColors {
+            title: colors.get::<_, Option<u32>>("title")?.unwrap_or(defaults.title),
+            focused_title: colors.get::<_, Option<u32>>("focused_title")?.unwrap_or(defaults.focused_title),
+            text: colors.get::<_, Option<u32>>("text")?.unwrap_or(defaults.text),
+            border: colors.get::<_, Option<u32>>("border")?.unwrap_or(defaults.border),
+        };
+    }
+
+    Ok(decorations)
+}
+
+fn parse_limits(table: &Table, defaults: Limits) -> rlua::Result<Limits> {
+    Ok(Limits {
+        frames: table.get::<_, Option<usize>>("frames")?.unwrap_or(defaults.frames),
+        memory: table.get::<_, Option<usize>>("memory")?.unwrap_or(defaults.memory),
+        size: match table.get::<_, Option<Table>>("size")? {
+            Some(size) => Size2D::new(size.get::<_, Option<i32>>("width")?.unwrap_or(defaults.size.width),
+                                      size.get::<_, Option<i32>>("height")?.unwrap_or(defaults.size.height)),
+            None => defaults.size
+        },
     })
 }
+
+/// Fetches an optional global, describing what went wrong if it has the wrong type
+fn get<'lua, T: FromLua<'lua>>(globals: &Table<'lua>, key: &str) -> Result<Option<T>, String> {
+    globals.get::<_, Option<T>>(key)
+        .map_err(|err| match err {
+            rlua::Error::FromLuaConversionError { from, to, message } => match message {
+                Some(message) => format!("expected {}, got {} ({})", to, from, message),
+                None => format!("expected {}, got {}", to, from)
+            },
+            err => err.to_string()
+        })
+}
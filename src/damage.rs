@@ -0,0 +1,39 @@
+use std::mem;
+
+use raqote::IntRect;
+
+/// Accumulates the regions of the compositor surface which have changed since the last composite.
+/// Overlapping regions are merged into their bounding box, so each pixel is only recomposited once per tick.
+#[derive(Debug, Clone, Default)]
+pub struct Damage {
+    rects: Vec<IntRect>,
+}
+
+impl Damage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, rect: IntRect) {
+        if rect.is_empty() {
+            return;
+        }
+
+        let mut rect = rect;
+
+        // merging two rects may cause the union to overlap a rect which was previously disjoint, so keep going until nothing changes
+        while let Some(index) = self.rects.iter().position(|i| i.intersects(&rect)) {
+            rect = rect.union(&self.rects.swap_remove(index));
+        }
+
+        self.rects.push(rect);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rects.is_empty()
+    }
+
+    pub fn take(&mut self) -> Vec<IntRect> {
+        mem::take(&mut self.rects)
+    }
+}
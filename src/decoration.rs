@@ -0,0 +1,213 @@
+use std::fs;
+
+use raqote::{DrawOptions, DrawTarget, IntPoint, IntRect, PathBuilder, Source, StrokeStyle};
+use rusttype::{point, Font, Scale};
+
+use crate::config::{solid_source, DecorationConfig};
+use crate::focus::Edges;
+use crate::frame::Frame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Close,
+    Maximise,
+    Minimise,
+}
+
+/// The part of a frame's decorations a point lies on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hit {
+    Title,
+    Button(Button),
+    Border(Edges),
+}
+
+/// Draws server-side decorations: a title bar above the frame, holding its title and buttons, and a border around both.
+pub struct Decorations {
+    pub config: DecorationConfig,
+    font: Font<'static>,
+}
+
+impl Decorations {
+    pub fn new(config: DecorationConfig) -> Result<Decorations, String> {
+        let data = fs::read(&config.font)
+            .map_err(|err| format!("Unable to read font {}: {}", config.font.display(), err))?;
+        let font = Font::try_from_vec(data)
+            .ok_or_else(|| format!("Invalid font {}", config.font.display()))?;
+
+        Ok(Self { config, font })
+    }
+
+    /// The area covered by a frame occupying `rect` and its decorations
+    pub fn outer_rect(&self, rect: &IntRect) -> IntRect {
+        let border = self.config.border;
+
+        IntRect::new(IntPoint::new(rect.min.x - border, rect.min.y - self.config.title_height - border),
+                     IntPoint::new(rect.max.x + border, rect.max.y + border))
+    }
+
+    fn title_rect(&self, rect: &IntRect) -> IntRect {
+        IntRect::new(IntPoint::new(rect.min.x, rect.min.y - self.config.title_height),
+                     IntPoint::new(rect.max.x, rect.min.y))
+    }
+
+    /// The buttons the frame allows, from right to left
+    fn buttons(frame: &Frame) -> Vec<Button> {
+        let mut buttons = Vec::new();
+
+        if frame.can_close {
+            buttons.push(Button::Close);
+        }
+        if frame.can_resize {
+            buttons.push(Button::Maximise);
+        }
+        if frame.can_minimise {
+            buttons.push(Button::Minimise);
+        }
+
+        buttons
+    }
+
+    pub fn button_rect(&self, frame: &Frame, button: Button) -> Option<IntRect> {
+        let index = Self::buttons(frame).iter().position(|i| *i == button)? as i32;
+        let title = self.title_rect(&frame.rect());
+        let size = self.config.title_height;
+        let right = title.max.x - size * index;
+
+        Some(IntRect::new(IntPoint::new(right - size, title.min.y), IntPoint::new(right, title.max.y)))
+    }
+
+    /// Which part of the decorations `point` lies on, if any
+    pub fn hit_test(&self, frame: &Frame, point: IntPoint) -> Option<Hit> {
+        let rect = frame.rect();
+        if rect.contains(point) || !self.outer_rect(&rect).contains(point) {
+            return None;
+        }
+
+        let button = Self::buttons(frame).into_iter()
+            .find(|button| self.button_rect(frame, *button).map_or(false, |i| i.contains(point)));
+
+        let title = self.title_rect(&rect);
+        match button {
+            Some(button) => Some(Hit::Button(button)),
+            None if title.contains(point) => Some(Hit::Title),
+            None => Some(Hit::Border(Edges::outside(&IntRect::new(title.min, rect.max), point)))
+        }
+    }
+
+    /// Draws the part of the frame's decorations which lies within `clip`
+    pub fn draw(&self, frame: &Frame, focused: bool, surface: &mut DrawTarget, clip: &IntRect) {
+        let rect = frame.rect();
+        let outer = self.outer_rect(&rect);
+        if !outer.intersects(clip) {
+            return;
+        }
+
+        let colors = &self.config.colors;
+        let title = self.title_rect(&rect);
+
+        surface.push_clip_rect(*clip);
+
+        let border = Source::Solid(solid_source(colors.border));
+        for strip in [
+            IntRect::new(outer.min, IntPoint::new(outer.max.x, title.min.y)),
+            IntRect::new(IntPoint::new(outer.min.x, rect.max.y), outer.max),
+            IntRect::new(IntPoint::new(outer.min.x, title.min.y), IntPoint::new(rect.min.x, rect.max.y)),
+            IntRect::new(IntPoint::new(rect.max.x, title.min.y), IntPoint::new(outer.max.x, rect.max.y)),
+        ] {
+            fill(surface, &strip, &border);
+        }
+
+        fill(surface, &title, &Source::Solid(solid_source(match focused {
+            true => colors.focused_title,
+            false => colors.title,
+        })));
+
+        let buttons = Self::buttons(frame);
+        for button in buttons.iter() {
+            if let Some(rect) = self.button_rect(frame, *button) {
+                self.draw_button(surface, *button, &rect);
+            }
+        }
+
+        surface.pop_clip();
+
+        let padding = self.config.title_height / 4;
+        let text = IntRect::new(IntPoint::new(title.min.x + padding, title.min.y),
+                                IntPoint::new(title.max.x - self.config.title_height * buttons.len() as i32, title.max.y));
+        if let Some(text_clip) = text.intersection(clip) {
+            self.draw_text(surface, &frame.title, &text, &text_clip);
+        }
+    }
+
+    fn draw_button(&self, surface: &mut DrawTarget, button: Button, rect: &IntRect) {
+        let inset = rect.width() as f32 / 3.;
+        let (min_x, min_y) = (rect.min.x as f32 + inset, rect.min.y as f32 + inset);
+        let (max_x, max_y) = (rect.max.x as f32 - inset, rect.max.y as f32 - inset);
+
+        let mut path = PathBuilder::new();
+        match button {
+            Button::Close => {
+                path.move_to(min_x, min_y);
+                path.line_to(max_x, max_y);
+                path.move_to(max_x, min_y);
+                path.line_to(min_x, max_y);
+            }
+            Button::Maximise => path.rect(min_x, min_y, max_x - min_x, max_y - min_y),
+            Button::Minimise => {
+                path.move_to(min_x, max_y);
+                path.line_to(max_x, max_y);
+            }
+        }
+
+        surface.stroke(&path.finish(),
+                       &Source::Solid(solid_source(self.config.colors.text)),
+                       &StrokeStyle { width: 1.5, ..StrokeStyle::default() },
+                       &DrawOptions::default());
+    }
+
+    /// Renders `text` vertically centred in `area`, touching only pixels within `clip`
+    fn draw_text(&self, surface: &mut DrawTarget, text: &str, area: &IntRect, clip: &IntRect) {
+        let scale = Scale::uniform(self.config.font_size);
+        let metrics = self.font.v_metrics(scale);
+        let baseline = area.min.y as f32 + (area.height() as f32 + metrics.ascent + metrics.descent) / 2.;
+
+        let width = surface.width();
+        let bounds = IntRect::new(IntPoint::zero(), IntPoint::new(width, surface.height()));
+        let color = self.config.colors.text;
+        let data = surface.get_data_mut();
+
+        for glyph in self.font.layout(text, scale, point(area.min.x as f32, baseline)) {
+            let Some(glyph_bounds) = glyph.pixel_bounding_box() else {
+                continue;
+            };
+
+            glyph.draw(|x, y, coverage| {
+                let pixel = IntPoint::new(glyph_bounds.min.x + x as i32, glyph_bounds.min.y + y as i32);
+                if clip.contains(pixel) && bounds.contains(pixel) {
+                    let index = (pixel.y * width + pixel.x) as usize;
+                    data[index] = blend(data[index], color, coverage);
+                }
+            });
+        }
+    }
+}
+
+fn fill(surface: &mut DrawTarget, rect: &IntRect, source: &Source) {
+    if !rect.is_empty() {
+        surface.fill_rect(rect.min.x as f32, rect.min.y as f32, rect.width() as f32, rect.height() as f32, source, &DrawOptions::default());
+    }
+}
+
+/// Blends an unpremultiplied ARGB colour with the given coverage over a premultiplied pixel
+fn blend(dst: u32, color: u32, coverage: f32) -> u32 {
+    let alpha = ((color >> 24) as f32 / 255.) * coverage;
+    let channel = |shift: u32| {
+        let src = ((color >> shift) & 0xff) as f32 * alpha;
+        let dst = ((dst >> shift) & 0xff) as f32 * (1. - alpha);
+        ((src + dst).round() as u32).min(0xff)
+    };
+    let a = ((alpha * 255. + ((dst >> 24) & 0xff) as f32 * (1. - alpha)).round() as u32).min(0xff);
+
+    (a << 24) | (channel(16) << 16) | (channel(8) << 8) | channel(0)
+}
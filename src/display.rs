@@ -5,29 +5,115 @@ use std::os::fd::AsRawFd;
 use std::path::Path;
 
 use euclid::{Size2D, UnknownUnit};
-use raqote::{DrawTarget, IntPoint, IntRect, PathBuilder, SolidSource};
+use raqote::{DrawTarget, IntPoint, IntRect};
 
-pub struct Display<'a> {
-    pub surface: DrawTarget<&'a mut [u32]>,
-    // surface: Vec<u32>,
-    backing: File,
+/// Anything the compositor can present its surface on.
+/// A backend exposes a mapped pixel buffer of a fixed size, and is told which regions of it changed.
+pub trait DisplayBackend {
+    fn size(&self) -> Size2D<i32, UnknownUnit>;
+
+    fn surface(&mut self) -> &mut [u32];
+
+    fn sync(&mut self, rect: SyncRect) -> Result<(), String>;
+}
+
+pub struct Display {
+    backend: Box<dyn DisplayBackend>,
 
-    // pub size: Size2D<i32, UnknownUnit>,
+    pub name: String,
     pub pos: IntPoint,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 #[repr(packed)]
-struct SyncRect {
-    x: i32,
-    y: i32,
-    w: i32,
-    h: i32,
+pub struct SyncRect {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+/// The prefix used in `Config::displays` to select a headless in-memory display, eg. `memory:1920x1080`
+pub const MEMORY_PREFIX: &'static str = "memory:";
+
+impl Display {
+    pub fn new(display: &str, pos: &IntPoint) -> Result<Display, String> {
+        let backend: Box<dyn DisplayBackend> = match display.strip_prefix(MEMORY_PREFIX) {
+            Some(size) => Box::new(MemoryBackend::from_spec(size)?),
+            None => Box::new(SchemeBackend::new(display)?)
+        };
+
+        Ok(Self::with_backend(display, pos, backend))
+    }
+
+    pub fn with_backend(name: &str, pos: &IntPoint, backend: Box<dyn DisplayBackend>) -> Display {
+        let mut display = Self {
+            name: name.to_owned(),
+            pos: pos.clone(),
+            backend,
+        };
+
+        display.backend.surface().fill(0xffffffff);
+        display.sync();
+        display
+    }
+
+    pub fn size(&self) -> Size2D<i32, UnknownUnit> {
+        self.backend.size()
+    }
+
+    pub fn rect(&self) -> IntRect {
+        IntRect::from_origin_and_size(self.pos, self.size())
+    }
+
+    /// The pixels last presented on the display
+    pub fn surface(&mut self) -> &mut [u32] {
+        self.backend.surface()
+    }
+
+    fn sync(&mut self) {
+        let Size2D { width, height, .. } = self.size();
+
+        if let Err(err) = self.backend.sync(SyncRect { x: 0, y: 0, w: width, h: height }) {
+            eprintln!("Failed to sync display {}: {}", self.name, err);
+        }
+    }
+
+    /// Copies the damaged regions of the compositor surface onto the display, syncing only those regions
+    pub fn draw(&mut self, surface: &mut DrawTarget, damage: &[IntRect]) {
+        let bounds = self.rect();
+        let size = self.size();
+        let mut target = DrawTarget::from_backing(size.width, size.height, self.backend.surface());
+
+        let dirty: Vec<IntRect> = damage.iter()
+            .filter_map(|rect| rect.intersection(&bounds))
+            .map(|rect| rect.translate(-self.pos.to_vector()))
+            .collect();
+
+        for rect in dirty.iter() {
+            target.copy_surface(surface, rect.translate(self.pos.to_vector()), rect.min);
+        }
+
+        drop(target);
+
+        for rect in dirty {
+            if let Err(err) = self.backend.sync(SyncRect { x: rect.min.x, y: rect.min.y, w: rect.width(), h: rect.height() }) {
+                eprintln!("Failed to sync display {}: {}", self.name, err);
+            }
+        }
+    }
 }
 
-impl<'a> Display<'a> {
-    pub fn new(display: &str, pos: &IntPoint) -> Result<Display<'a>, String> {
-        let mut backing = match OpenOptions::new()
+/// Presents to a Redox `display:` scheme handle by mapping its framebuffer into memory.
+pub struct SchemeBackend {
+    backing: File,
+    surface: &'static mut [u32],
+    size: Size2D<i32, UnknownUnit>,
+}
+
+impl SchemeBackend {
+    pub fn new(display: &str) -> Result<SchemeBackend, String> {
+        let backing = match OpenOptions::new()
             .read(true)
             .write(true)
             .open(display) {
@@ -37,7 +123,8 @@ impl<'a> Display<'a> {
 
         let (width, height) = {
             let mut buf: [u8; 4096] = [0; 4096];
-            let count = syscall::fpath(backing.as_raw_fd() as usize, &mut buf).unwrap();
+            let count = syscall::fpath(backing.as_raw_fd() as usize, &mut buf)
+                .map_err(|err| format!("Unable to query display {}: {}", display, err))?;
 
             println!("Opening {}", String::from_utf8_lossy(&buf[..count]));
 
@@ -47,8 +134,8 @@ impl<'a> Display<'a> {
             };
 
             let mut url_parts = url.split(':');
-            let _scheme_name = url_parts.next().unwrap();
-            let path = url_parts.next().unwrap();
+            let _scheme_name = url_parts.next().unwrap_or("");
+            let path = url_parts.next().unwrap_or("");
 
             let mut path_parts = path.split('/').skip(1);
 
@@ -58,46 +145,87 @@ impl<'a> Display<'a> {
 
         println!("Success - {}x{}", &width, &height);
 
-        let mut surface = unsafe {
+        let surface = unsafe {
             let ptr = syscall::fmap(backing.as_raw_fd() as usize, &syscall::Map {
                 offset: 0,
                 size: (width * height * 4) as usize,
                 flags: syscall::PROT_READ | syscall::PROT_WRITE,
                 address: 0,
-            }).unwrap();
+            }).map_err(|err| format!("Unable to map display {}: {}", display, err))?;
 
-            DrawTarget::from_backing(width as i32, height as i32, slice::from_raw_parts_mut(ptr as *mut u32, (width * height) as usize))
+            slice::from_raw_parts_mut(ptr as *mut u32, (width * height) as usize)
         };
 
-        surface.clear(SolidSource { r: 0xff, g: 0xff, b: 0xff, a: 0xff });
-
-        let mut display = Self {
-            pos: pos.clone(),
+        Ok(Self {
             backing,
             surface,
-        };
-        display.sync();
-        Ok(display)
+            size: Size2D::new(width as i32, height as i32),
+        })
     }
+}
 
-    fn sync(&mut self) {
-        self.backing.write(unsafe {
-            slice::from_raw_parts(
-                &(SyncRect {
-                    x: 0,
-                    y: 0,
-                    w: self.surface.width(),
-                    h: self.surface.height(),
-                }) as *const SyncRect as *const u8,
-                mem::size_of::<SyncRect>())
-        }).unwrap();
-        syscall::fsync(self.backing.as_raw_fd() as usize).unwrap();
-    }
-
-    pub fn draw(&mut self, surface: &mut DrawTarget) {
-        let size = Size2D::new(self.surface.width(), self.surface.height());
-        self.surface.copy_surface(surface, IntRect::from_origin_and_size(self.pos, size), IntPoint::new(0, 0));
-
-        self.sync();
+impl Drop for SchemeBackend {
+    fn drop(&mut self) {
+        let _ = unsafe { syscall::funmap(self.surface.as_ptr() as usize, self.surface.len() * mem::size_of::<u32>()) };
+    }
+}
+
+impl DisplayBackend for SchemeBackend {
+    fn size(&self) -> Size2D<i32, UnknownUnit> {
+        self.size
+    }
+
+    fn surface(&mut self) -> &mut [u32] {
+        self.surface
+    }
+
+    fn sync(&mut self, rect: SyncRect) -> Result<(), String> {
+        self.backing.write_all(unsafe {
+            slice::from_raw_parts(&rect as *const SyncRect as *const u8, mem::size_of::<SyncRect>())
+        }).map_err(|err| err.to_string())?;
+
+        syscall::fsync(self.backing.as_raw_fd() as usize)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// A headless framebuffer living entirely in memory. Nothing is presented anywhere,
+/// which allows the compositor to run off-Redox, eg. in CI.
+pub struct MemoryBackend {
+    surface: Vec<u32>,
+    size: Size2D<i32, UnknownUnit>,
+}
+
+impl MemoryBackend {
+    pub fn new(size: Size2D<i32, UnknownUnit>) -> MemoryBackend {
+        Self {
+            surface: vec![0; (size.width * size.height) as usize],
+            size,
+        }
+    }
+
+    /// Parses a `WIDTHxHEIGHT` size specification
+    pub fn from_spec(spec: &str) -> Result<MemoryBackend, String> {
+        let mut parts = spec.split('x');
+
+        match (parts.next().and_then(|w| w.parse::<i32>().ok()), parts.next().and_then(|h| h.parse::<i32>().ok())) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => Ok(Self::new(Size2D::new(width, height))),
+            _ => Err(format!("Invalid memory display size '{}', expected WIDTHxHEIGHT", spec))
+        }
+    }
+}
+
+impl DisplayBackend for MemoryBackend {
+    fn size(&self) -> Size2D<i32, UnknownUnit> {
+        self.size
+    }
+
+    fn surface(&mut self) -> &mut [u32] {
+        &mut self.surface
+    }
+
+    fn sync(&mut self, _rect: SyncRect) -> Result<(), String> {
+        Ok(())
     }
 }
@@ -0,0 +1,125 @@
+use std::{mem, slice};
+
+use crate::frame::{FrameEvent, ZIndex};
+
+pub const EVENT_MOUSE_MOVE: u32 = 1;
+pub const EVENT_MOUSE_BUTTON: u32 = 2;
+pub const EVENT_MOUSE_SCROLL: u32 = 3;
+pub const EVENT_KEY: u32 = 4;
+pub const EVENT_FOCUS: u32 = 5;
+pub const EVENT_RESIZE: u32 = 6;
+pub const EVENT_CLOSE: u32 = 7;
+pub const EVENT_MOVE: u32 = 8;
+pub const EVENT_PROPERTY: u32 = 9;
+pub const EVENT_BACKDROP: u32 = 10;
+pub const EVENT_DESTROY: u32 = 11;
+
+/// Identifies the property an `EVENT_PROPERTY` reports a change to
+pub const PROPERTY_TITLE: u32 = 1;
+pub const PROPERTY_PARENT: u32 = 2;
+pub const PROPERTY_Z_INDEX: u32 = 3;
+pub const PROPERTY_CAN_MINIMISE: u32 = 4;
+pub const PROPERTY_CAN_RESIZE: u32 = 5;
+pub const PROPERTY_CAN_CLOSE: u32 = 6;
+pub const PROPERTY_TRANSPARENT: u32 = 7;
+pub const PROPERTY_OPACITY: u32 = 8;
+
+/// The most events a frame will hold before the oldest are dropped
+pub const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// Events delivered to the client owning a frame, by `read`ing from the frame's handle.
+/// Pointer coordinates are relative to the frame's origin.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientEvent {
+    MouseMove { x: i32, y: i32 },
+    MouseButton { button: u8, pressed: bool },
+    MouseScroll { x: i32, y: i32 },
+    Key { scancode: u8, character: char, pressed: bool },
+    Focus(bool),
+    Resize { width: i32, height: i32 },
+    CloseRequested,
+    Move { x: i32, y: i32 },
+    Property { property: u32, value: i32 },
+    /// What lies beneath the frame was recomposited into its backdrop buffer
+    Backdrop,
+    /// The frame was destroyed by someone else, eg. along with its parent. The handle only reads the events left.
+    Destroyed,
+}
+
+impl ClientEvent {
+    /// What the client is told when a property of its frame changes, or it's destroyed. Creation isn't reported.
+    pub fn from_frame_event(event: &FrameEvent) -> Option<ClientEvent> {
+        let property = |property: u32, value: i32| Some(ClientEvent::Property { property, value });
+
+        match event {
+            FrameEvent::Created(_) => None,
+            FrameEvent::Destroyed(_) => Some(ClientEvent::Destroyed),
+            FrameEvent::SizeChanged(_, size) => Some(ClientEvent::Resize { width: size.width, height: size.height }),
+            FrameEvent::PosChanged(_, pos) => Some(ClientEvent::Move { x: pos.x, y: pos.y }),
+            FrameEvent::TitleChanged(_, _) => property(PROPERTY_TITLE, 0),
+            FrameEvent::ParentChanged(_, parent) => property(PROPERTY_PARENT, *parent as i32),
+            FrameEvent::ZIndexChanged(_, z_index) => property(PROPERTY_Z_INDEX, match z_index {
+                ZIndex::Back => -1,
+                ZIndex::Auto => 0,
+                ZIndex::Front => 1,
+            }),
+            FrameEvent::CanMinimiseChanged(_, value) => property(PROPERTY_CAN_MINIMISE, *value as i32),
+            FrameEvent::CanResizeChanged(_, value) => property(PROPERTY_CAN_RESIZE, *value as i32),
+            FrameEvent::CanCloseChanged(_, value) => property(PROPERTY_CAN_CLOSE, *value as i32),
+            FrameEvent::TransparentChanged(_, value) => property(PROPERTY_TRANSPARENT, *value as i32),
+            FrameEvent::OpacityChanged(_, value) => property(PROPERTY_OPACITY, (value * 255.).round() as i32),
+        }
+    }
+}
+
+/// The binary layout of a `ClientEvent`. Reads always return a whole number of these records.
+///
+/// | kind                 | a        | b       | c         |
+/// |----------------------|----------|---------|-----------|
+/// | `EVENT_MOUSE_MOVE`   | x        | y       |           |
+/// | `EVENT_MOUSE_BUTTON` | button   | pressed |           |
+/// | `EVENT_MOUSE_SCROLL` | x        | y       |           |
+/// | `EVENT_KEY`          | scancode | pressed | character |
+/// | `EVENT_FOCUS`        | focused  |         |           |
+/// | `EVENT_RESIZE`       | width    | height  |           |
+/// | `EVENT_CLOSE`        |          |         |           |
+/// | `EVENT_MOVE`         | x        | y       |           |
+/// | `EVENT_PROPERTY`     | property | value   |           |
+/// | `EVENT_BACKDROP`     |          |         |           |
+/// | `EVENT_DESTROY`      |          |         |           |
+///
+/// `EVENT_MOVE` gives the frame's position relative to its parent, or to the surface if it has none.
+/// For `EVENT_PROPERTY`, booleans are `0` or `1`, the z-index is `-1`, `0` or `1` for back, auto and front,
+/// and opacity ranges from `0` to `255`.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct RawEvent {
+    pub kind: u32,
+    pub a: i32,
+    pub b: i32,
+    pub c: i32,
+}
+
+impl RawEvent {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const RawEvent as *const u8, mem::size_of::<RawEvent>()) }
+    }
+}
+
+impl From<ClientEvent> for RawEvent {
+    fn from(event: ClientEvent) -> Self {
+        match event {
+            ClientEvent::MouseMove { x, y } => RawEvent { kind: EVENT_MOUSE_MOVE, a: x, b: y, c: 0 },
+            ClientEvent::MouseButton { button, pressed } => RawEvent { kind: EVENT_MOUSE_BUTTON, a: button as i32, b: pressed as i32, c: 0 },
+            ClientEvent::MouseScroll { x, y } => RawEvent { kind: EVENT_MOUSE_SCROLL, a: x, b: y, c: 0 },
+            ClientEvent::Key { scancode, character, pressed } => RawEvent { kind: EVENT_KEY, a: scancode as i32, b: pressed as i32, c: character as i32 },
+            ClientEvent::Focus(focused) => RawEvent { kind: EVENT_FOCUS, a: focused as i32, b: 0, c: 0 },
+            ClientEvent::Resize { width, height } => RawEvent { kind: EVENT_RESIZE, a: width, b: height, c: 0 },
+            ClientEvent::CloseRequested => RawEvent { kind: EVENT_CLOSE, a: 0, b: 0, c: 0 },
+            ClientEvent::Move { x, y } => RawEvent { kind: EVENT_MOVE, a: x, b: y, c: 0 },
+            ClientEvent::Property { property, value } => RawEvent { kind: EVENT_PROPERTY, a: property as i32, b: value, c: 0 },
+            ClientEvent::Backdrop => RawEvent { kind: EVENT_BACKDROP, a: 0, b: 0, c: 0 },
+            ClientEvent::Destroyed => RawEvent { kind: EVENT_DESTROY, a: 0, b: 0, c: 0 },
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use raqote::{IntPoint, IntRect};
+
+/// How keyboard focus follows the pointer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FocusMode {
+    /// Frames are focused (and raised) when clicked
+    ClickToFocus,
+    /// The frame under the pointer is focused. Frames are still only raised when clicked.
+    FollowsMouse,
+}
+
+impl FocusMode {
+    pub fn from_str(mode: &str) -> Option<FocusMode> {
+        match mode {
+            "click" => Some(FocusMode::ClickToFocus),
+            "mouse" => Some(FocusMode::FollowsMouse),
+            _ => None
+        }
+    }
+}
+
+/// Tracks which frames receive input.
+/// Key events go to the `focused` frame. Pointer events go to the frame under the cursor,
+/// unless a button is held, in which case they keep going to the frame the press started on.
+#[derive(Debug, Clone)]
+pub struct Focus {
+    pub mode: FocusMode,
+    pub focused: Option<usize>,
+    pub hovered: Option<usize>,
+    pub grab: Option<usize>,
+    pub buttons: u8,
+    /// Whether the modifier allowing frames to be dragged from anywhere is held
+    pub modifier: bool,
+    /// A frame being moved or resized by the pointer. Pointer events aren't delivered while dragging.
+    pub drag: Option<Drag>,
+    /// Scancodes of the held keys
+    pub keys: Vec<u8>,
+    /// Scancodes of the keys released since input was last polled
+    pub released: Vec<u8>,
+    /// How far the wheel scrolled since input was last polled
+    pub scroll: (i32, i32),
+}
+
+impl Focus {
+    pub fn new(mode: FocusMode) -> Self {
+        Self {
+            mode,
+            focused: None,
+            hovered: None,
+            grab: None,
+            buttons: 0,
+            modifier: false,
+            drag: None,
+            keys: Vec::new(),
+            released: Vec::new(),
+            scroll: (0, 0),
+        }
+    }
+
+    /// The frame pointer events are delivered to
+    pub fn pointer_target(&self) -> Option<usize> {
+        match self.drag {
+            Some(_) => None,
+            None => self.grab.or(self.hovered)
+        }
+    }
+
+    /// Forgets a frame which no longer exists
+    pub fn remove(&mut self, id: usize) {
+        if self.focused == Some(id) {
+            self.focused = None;
+        }
+        if self.hovered == Some(id) {
+            self.hovered = None;
+        }
+        if self.grab == Some(id) {
+            self.grab = None;
+        }
+        if self.drag.map_or(false, |drag| drag.id == id) {
+            self.drag = None;
+        }
+    }
+}
+
+/// The sides of a frame being resized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Edges {
+    pub left: bool,
+    pub right: bool,
+    pub top: bool,
+    pub bottom: bool,
+}
+
+impl Edges {
+    /// The edges of `rect` which `point` lies beyond
+    pub fn outside(rect: &IntRect, point: IntPoint) -> Edges {
+        Edges {
+            left: point.x < rect.min.x,
+            right: point.x >= rect.max.x,
+            top: point.y < rect.min.y,
+            bottom: point.y >= rect.max.y,
+        }
+    }
+
+    /// The corner of `rect` closest to `point`
+    pub fn nearest(rect: &IntRect, point: IntPoint) -> Edges {
+        let centre = rect.center();
+
+        Edges {
+            left: point.x < centre.x,
+            right: point.x >= centre.x,
+            top: point.y < centre.y,
+            bottom: point.y >= centre.y,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DragKind {
+    Move,
+    Resize(Edges),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Drag {
+    pub id: usize,
+    pub kind: DragKind,
+    /// Where the cursor was when the drag started
+    pub start: IntPoint,
+    /// The frame's rect when the drag started
+    pub rect: IntRect,
+}
+
+impl Drag {
+    /// The rect the frame follows the pointer to, before its size limits are applied
+    pub fn rect(&self, cursor: IntPoint) -> IntRect {
+        let delta = cursor - self.start;
+        let mut rect = self.rect;
+
+        match self.kind {
+            DragKind::Move => return rect.translate(delta),
+            DragKind::Resize(edges) => {
+                if edges.left {
+                    rect.min.x = (rect.min.x + delta.x).min(rect.max.x);
+                }
+                if edges.right {
+                    rect.max.x = (rect.max.x + delta.x).max(rect.min.x);
+                }
+                if edges.top {
+                    rect.min.y = (rect.min.y + delta.y).min(rect.max.y);
+                }
+                if edges.bottom {
+                    rect.max.y = (rect.max.y + delta.y).max(rect.min.y);
+                }
+            }
+        }
+
+        rect
+    }
+}
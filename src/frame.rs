@@ -1,21 +1,207 @@
 use std::{mem, slice};
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 
-use euclid::{Box2D, Point2D, Size2D, UnknownUnit};
-use raqote::{DrawOptions, DrawTarget, IntPoint, IntRect, SolidSource, Source};
+use euclid::{Point2D, Size2D, UnknownUnit};
+use raqote::{BlendMode, DrawOptions, DrawTarget, IntPoint, IntRect, SolidSource, Source};
 use rlua::{Context, FromLua, Table, ToLua, Value};
 use rlua::prelude::LuaTable;
 use syscall::PAGE_SIZE;
 
-use crate::bin::aligned_vec;
+use crate::bin::{BufferView, PageBuffer};
+use crate::config::solid_source;
+use crate::event::{ClientEvent, MAX_QUEUED_EVENTS};
 
-pub struct Frame<'a> {
+pub struct Frame {
     pub id: usize,
+    /// On the surface. Clients and plugins give positions relative to the parent's instead.
     pub pos: IntPoint,
-    pub surface: DrawTarget<&'a mut [u32]>,
+    pub buffers: Buffers,
     pub last_update: Instant,
+    /// Children move with their parent, are stacked directly above it and are destroyed along with it
     pub parent: Option<usize>,
     pub title: String,
+    pub z_index: ZIndex,
+    pub events: VecDeque<ClientEvent>,
+    pub owner: Owner,
+    /// Users besides the owner who may parent frames to this one and see it in their backdrops
+    pub grants: Vec<u32>,
+
+    pub min_size: Size2D<i32, UnknownUnit>,
+    pub max_size: Size2D<i32, UnknownUnit>,
+    pub can_minimise: bool,
+    pub can_resize: bool,
+    pub can_close: bool,
+    /// Transparent frames are blended over what lies beneath them, and must hold premultiplied ARGB
+    pub transparent: bool,
+    /// Multiplied into the frame's alpha, from `0` to `1`
+    pub opacity: f32,
+    pub decorated: bool,
+    pub minimised: bool,
+    /// The rect to return to when a maximised frame is restored
+    pub restore: Option<IntRect>,
+
+    /// Whether the client has mapped the current buffers
+    pub mapped: bool,
+    /// Buffers replaced by a resize which the client may still have mapped, oldest first.
+    /// They are kept until the client acknowledges the swap by syncing after mapping the current buffers.
+    pub retired: Vec<Buffers>,
+}
+
+/// Whoever created a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Owner {
+    /// The process which opened the frame through the scheme. The frame's id doubles as the client's handle to it.
+    Client { uid: u32, gid: u32, pid: usize },
+    /// The plugin at this path
+    Plugin(String),
+}
+
+impl Owner {
+    /// The user the frame belongs to. Frames created by plugins don't belong to any.
+    pub fn uid(&self) -> Option<u32> {
+        match self {
+            Owner::Client { uid, .. } => Some(*uid),
+            Owner::Plugin(_) => None,
+        }
+    }
+}
+
+/// How many buffers a frame has. With more than one, the compositor only reads buffers the client has finished drawing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferMode {
+    Single,
+    Double,
+    Triple,
+}
+
+impl BufferMode {
+    pub fn from_count(count: usize) -> Option<BufferMode> {
+        match count {
+            1 => Some(BufferMode::Single),
+            2 => Some(BufferMode::Double),
+            3 => Some(BufferMode::Triple),
+            _ => None
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        match self {
+            BufferMode::Single => 1,
+            BufferMode::Double => 2,
+            BufferMode::Triple => 3,
+        }
+    }
+}
+
+/// A frame's buffers, laid out back to back in one page-aligned allocation so they can be mapped together.
+/// Buffer `i` starts `i * stride()` bytes into the mapping.
+///
+/// The compositor reads the `front` buffer while the client draws into the `back` buffer.
+/// Swapping makes the back buffer the front, and moves the client on to the next buffer.
+/// If the frame has a backdrop, it follows the last buffer, and is written by the compositor.
+pub struct Buffers {
+    // the targets view `memory`, so must be dropped first
+    targets: Vec<DrawTarget<BufferView>>,
+    memory: PageBuffer,
+    backdrop: bool,
+    pub front: usize,
+    pub back: usize,
+}
+
+impl Buffers {
+    /// Fails with `ENOMEM` if the buffers can't be allocated
+    pub fn new(size: Size2D<i32, UnknownUnit>, mode: BufferMode, backdrop: bool, fill: u32) -> Result<Buffers, i32> {
+        let (width, height) = (size.width.max(0), size.height.max(0));
+        let len = (width as usize).checked_mul(height as usize).ok_or(syscall::ENOMEM)?;
+        let stride = Self::stride_of(len).ok_or(syscall::ENOMEM)? / mem::size_of::<u32>();
+        let count = mode.count() + backdrop as usize;
+
+        let total = stride.checked_mul(count).ok_or(syscall::ENOMEM)?;
+        let mut memory = PageBuffer::new(total, fill).map_err(|err| err.errno)?;
+
+        // the backdrop starts out transparent
+        memory.as_mut_slice()[stride * mode.count()..].fill(0);
+
+        Ok(Self {
+            targets: (0..count)
+                .map(|i| DrawTarget::from_backing(width, height, unsafe { memory.view(i * stride, len) }))
+                .collect(),
+            memory,
+            backdrop,
+            front: 0,
+            back: 1 % mode.count(),
+        })
+    }
+
+    /// The memory `Buffers::new` would allocate, in bytes. `usize::MAX` if that doesn't fit in memory.
+    pub fn bytes_for(size: Size2D<i32, UnknownUnit>, mode: BufferMode, backdrop: bool) -> usize {
+        (size.width.max(0) as usize).checked_mul(size.height.max(0) as usize)
+            .and_then(Self::stride_of)
+            .and_then(|stride| stride.checked_mul(mode.count() + backdrop as usize))
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Buffers start on page boundaries, so each can be mapped on its own
+    fn stride_of(len: usize) -> Option<usize> {
+        len.checked_mul(mem::size_of::<u32>())?.max(1).checked_add(PAGE_SIZE - 1).map(|bytes| bytes & !(PAGE_SIZE - 1))
+    }
+
+    pub fn mode(&self) -> BufferMode {
+        BufferMode::from_count(self.count()).unwrap_or(BufferMode::Single)
+    }
+
+    /// The number of buffers the client draws into
+    fn count(&self) -> usize {
+        self.targets.len() - self.backdrop as usize
+    }
+
+    pub fn has_backdrop(&self) -> bool {
+        self.backdrop
+    }
+
+    /// The buffer holding everything beneath the frame
+    pub fn backdrop_mut(&mut self) -> Option<&mut DrawTarget<BufferView>> {
+        match self.backdrop {
+            true => self.targets.last_mut(),
+            false => None
+        }
+    }
+
+    pub fn size(&self) -> Size2D<i32, UnknownUnit> {
+        Size2D::new(self.targets[0].width(), self.targets[0].height())
+    }
+
+    /// The distance in bytes between the start of two buffers
+    pub fn stride(&self) -> usize {
+        self.memory.bytes() / self.targets.len()
+    }
+
+    /// The buffer the compositor draws from
+    pub fn front(&self) -> &DrawTarget<BufferView> {
+        &self.targets[self.front]
+    }
+
+    pub fn front_mut(&mut self) -> &mut DrawTarget<BufferView> {
+        &mut self.targets[self.front]
+    }
+
+    /// Presents the back buffer, returning the index of the buffer the client should draw into next
+    pub fn swap(&mut self) -> usize {
+        self.front = self.back;
+        self.back = (self.back + 1) % self.count();
+        self.back
+    }
+
+    /// The start of the allocation, and its length in bytes
+    pub(crate) fn mut_ptr(&mut self) -> (*mut u8, usize) {
+        (self.memory.as_mut_ptr() as *mut u8, self.memory.bytes())
+    }
+
+    /// The memory held by the buffers, in bytes
+    pub fn bytes(&self) -> usize {
+        self.memory.bytes()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,62 +216,175 @@ pub struct FrameMessenger {
 
 /// Frames are _Windows_ to the client. They are anything the compositor displays. They capture and use input, and can be placed arbitrarily on the screen.
 /// Applications controlling frames typically use the following architecture:
-///  * Each client opens two buffers - front and back buffers.
-///  * The client draws to the back buffer, and then swaps the buffers.
-/// Optionally, the client can request a third, unrelated buffer be populated with the contents beneath the frame.
+///  * Each client opens two buffers - front and back buffers - with the `buffers=2` option, and maps both.
+///  * The client draws to the back buffer, and then swaps the buffers with `fsync`, which returns the next back buffer.
+/// Optionally, the client can request another, unrelated buffer be populated with the contents beneath the frame, with the `backdrop` option.
 /// - This can be used to create systems ranging from blurred backgrounds to screen readers.
-impl<'a> Frame<'a> {
-    pub fn new(options: FrameOptions, id: usize) -> Result<Frame<'a>, i32> {
-        let Size2D { width, height, .. } = options.size;
-        let mut surface = DrawTarget::from_backing(width, height, unsafe {
-            let layout = std::alloc::Layout::from_size_align(mem::size_of::<u32>() * (width * height) as usize, PAGE_SIZE).unwrap();
-            let ptr = std::alloc::alloc(layout) as *mut u32;
-            std::slice::from_raw_parts_mut::<u32>(ptr, (width * height) as usize)
-        });
-
-        surface.clear(SolidSource::from_unpremultiplied_argb(0xff, 0xaa, 0xaa, 0xaa));
+impl Frame {
+    pub fn new(options: FrameOptions, id: usize, owner: Owner, fill: u32) -> Result<Frame, i32> {
+        let buffers = Buffers::new(options.size, options.buffers, options.backdrop, fill)?;
 
         Ok(Self {
             pos: options.pos.clone(),
             id,
-            surface,
-            // size: options.size.clone(),
+            buffers,
             title: options.title,
             parent: options.parent,
+            z_index: options.z_lock,
+            events: VecDeque::new(),
+            owner,
+            grants: Vec::new(),
+            min_size: options.min_size,
+            max_size: options.max_size,
+            can_minimise: options.can_minimise,
+            can_resize: options.can_resize,
+            can_close: options.can_close,
+            transparent: options.transparent,
+            opacity: 1.,
+            decorated: options.decorated,
+            minimised: false,
+            restore: None,
+            mapped: false,
+            retired: Vec::new(),
             last_update: Instant::now(),
         })
     }
 
-    pub(crate) fn mut_ptr(&mut self) -> (*mut u32, usize) {
-        let ptr = self.surface.get_data_mut().as_mut_ptr();
-        let len = self.surface.get_data().len();
-        (ptr, len)
+    pub fn size(&self) -> Size2D<i32, UnknownUnit> {
+        self.buffers.size()
     }
 
-    pub fn draw(&mut self, surface: &mut DrawTarget) {
-        let size = Size2D::new(self.surface.width(), self.surface.height());
-        let rect = Box2D::from_origin_and_size(self.pos, size);
-        surface.copy_surface(&mut self.surface, rect.clone(), Point2D::new(0, 0));
+    /// Replaces the frame's buffers with ones of the new size, clamped to the frame's size limits.
+    /// As much of the old contents as fits are kept, and the rest is filled with `fill`.
+    /// The new buffers start over, with the client drawing into buffer `1 % count`. Fails with `ENOMEM` like `Buffers::new`.
+    pub fn resize(&mut self, size: Size2D<i32, UnknownUnit>, fill: u32) -> Result<Size2D<i32, UnknownUnit>, i32> {
+        let size = size.clamp(self.min_size, self.max_size);
+        if size == self.size() {
+            return Ok(size);
+        }
+
+        let mut buffers = Buffers::new(size, self.buffers.mode(), self.buffers.has_backdrop(), fill)?;
+        buffers.front_mut().copy_surface(self.buffers.front(), IntRect::from_size(self.size()), IntPoint::new(0, 0));
+
+        // buffers the client never mapped can't be in use
+        let previous = mem::replace(&mut self.buffers, buffers);
+        if self.mapped {
+            self.retired.push(previous);
+        }
+        self.mapped = false;
+
+        Ok(size)
+    }
+
+    /// The pixel memory held by the frame, including retired buffers
+    pub fn bytes(&self) -> usize {
+        self.buffers.bytes() + self.retired.iter().map(|i| i.bytes()).sum::<usize>()
+    }
+
+    /// Releases the retired buffers once the client has switched to the current one
+    pub fn acknowledge(&mut self) {
+        if self.mapped {
+            self.retired.clear();
+        }
+    }
+
+    pub fn rect(&self) -> IntRect {
+        IntRect::from_origin_and_size(self.pos, self.size())
+    }
+
+    /// Whether the idle fade is still in progress, meaning the frame changes appearance without being updated
+    pub fn is_fading(&self) -> bool {
+        let elapsed = self.last_update.elapsed().as_secs_f64();
+        elapsed > 10. && elapsed < 12.5
+    }
+
+    /// Copies what has been composited beneath the frame within `clip` into its backdrop, returning whether anything changed.
+    /// The `hidden` rects are filled with `fill` instead.
+    pub fn capture_backdrop(&mut self, surface: &DrawTarget, clip: &IntRect, hidden: &[IntRect], fill: u32) -> bool {
+        let rect = self.rect();
+        let (Some(backdrop), Some(visible)) = (self.buffers.backdrop_mut(), rect.intersection(clip)) else {
+            return false;
+        };
+
+        backdrop.copy_surface(surface, visible, visible.min - rect.min.to_vector());
+
+        for hidden in hidden.iter().filter_map(|i| i.intersection(&visible)) {
+            let hidden = hidden.translate(-rect.min.to_vector());
+            backdrop.fill_rect(hidden.min.x as f32,
+                               hidden.min.y as f32,
+                               hidden.width() as f32,
+                               hidden.height() as f32,
+                               &Source::Solid(solid_source(fill)),
+                               &DrawOptions { blend_mode: BlendMode::Src, ..DrawOptions::default() });
+        }
+
+        true
+    }
+
+    /// Draws the part of the frame which lies within `clip`
+    pub fn draw(&mut self, surface: &mut DrawTarget, clip: &IntRect) {
+        let rect = self.rect();
+        let Some(visible) = rect.intersection(clip) else {
+            return;
+        };
+
+        self.present(surface, self.buffers.front(), visible.translate(-rect.min.to_vector()), visible.min);
+
+        // until the client switches buffers, it keeps drawing into the last ones it mapped
+        if !self.mapped {
+            if let Some(retired) = self.retired.last() {
+                if let Some(visible) = IntRect::from_origin_and_size(self.pos, retired.size()).intersection(&visible) {
+                    self.present(surface, retired.front(), visible.translate(-rect.min.to_vector()), visible.min);
+                }
+            }
+        }
 
         let elapsed = self.last_update.elapsed().as_secs_f64();
         if elapsed > 10. {
             let alpha = 255. * ((elapsed - 10.) / 5.).clamp(0., 0.5); // fade to 50% alpha over 2.5s
 
-            surface.fill_rect(rect.min.x as f32,
-                              rect.min.y as f32,
-                              rect.width() as f32,
-                              rect.height() as f32,
+            surface.fill_rect(visible.min.x as f32,
+                              visible.min.y as f32,
+                              visible.width() as f32,
+                              visible.height() as f32,
                               &Source::Solid(SolidSource::from_unpremultiplied_argb(alpha as u8, 0xff, 0xff, 0xff)),
                               &DrawOptions::default(),
             );
         }
     }
 
+    /// Opaque frames are copied straight onto the surface. Anything else is blended over it.
+    fn present(&self, surface: &mut DrawTarget, buffer: &DrawTarget<BufferView>, src: IntRect, dst: IntPoint) {
+        match (self.transparent, self.opacity) {
+            (false, opacity) if opacity >= 1. => surface.copy_surface(buffer, src, dst),
+            (true, opacity) if opacity >= 1. => surface.blend_surface(buffer, src, dst, BlendMode::SrcOver),
+            (true, opacity) => surface.blend_surface_with_alpha(buffer, src, dst, opacity),
+            // the alpha channel of an opaque frame means nothing, so it's treated as fully opaque
+            (false, opacity) => {
+                let alpha = (opacity.max(0.) * 256.) as u32;
+                surface.composite_surface(buffer, src, dst, |src, dst| {
+                    for (src, dst) in src.iter().zip(dst.iter_mut()) {
+                        *dst = fade(*src | 0xff000000, *dst, alpha);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Queues an event for the client to read. If the client isn't keeping up, the oldest events are discarded.
+    pub fn push_event(&mut self, event: ClientEvent) {
+        if self.events.len() >= MAX_QUEUED_EVENTS {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(event);
+    }
+
     pub fn get_messenger(&self) -> FrameMessenger {
         FrameMessenger {
             id: self.id,
             pos: self.pos,
-            size: Size2D::new(self.surface.width(), self.surface.height()),
+            size: self.size(),
             last_update: self.last_update,
             parent: self.parent,
             title: self.title.clone(),
@@ -95,22 +394,22 @@ impl<'a> Frame<'a> {
 
 impl<'lua> ToLua<'lua> for FrameMessenger {
     fn to_lua(self, lua: Context<'lua>) -> rlua::Result<Value<'lua>> {
-        let val = lua.create_table().unwrap();
+        let val = lua.create_table()?;
 
-        let pos = lua.create_table().unwrap();
-        pos.set("x", self.pos.x).unwrap();
-        pos.set("y", self.pos.y).unwrap();
-        val.set("pos", pos).unwrap();
+        let pos = lua.create_table()?;
+        pos.set("x", self.pos.x)?;
+        pos.set("y", self.pos.y)?;
+        val.set("pos", pos)?;
 
-        let size = lua.create_table().unwrap();
-        size.set("width", self.size.width).unwrap();
-        size.set("height", self.size.height).unwrap();
-        val.set("size", size).unwrap();
+        let size = lua.create_table()?;
+        size.set("width", self.size.width)?;
+        size.set("height", self.size.height)?;
+        val.set("size", size)?;
 
-        val.set("id", self.id).unwrap();
-        val.set("title", self.title).unwrap();
-        val.set("parent", self.parent).unwrap();
-        val.set("last_update", self.last_update.elapsed().as_secs_f64()).unwrap();
+        val.set("id", self.id)?;
+        val.set("title", self.title)?;
+        val.set("parent", self.parent)?;
+        val.set("last_update", self.last_update.elapsed().as_secs_f64())?;
 
         // TODO: Allow Lua to set the buffer, as well as a refresh method.
 
@@ -121,18 +420,18 @@ impl<'lua> ToLua<'lua> for FrameMessenger {
 impl<'lua> FromLua<'lua> for FrameMessenger {
     fn from_lua(lua_value: Value<'lua>, lua: Context<'lua>) -> rlua::Result<Self> {
         if let Value::Table(table) = lua_value {
-            let pos = table.get::<_, LuaTable>("pos").unwrap();
-            let x = pos.get::<_, i32>("x").unwrap();
-            let y = pos.get::<_, i32>("y").unwrap();
+            let pos = table.get::<_, LuaTable>("pos")?;
+            let x = pos.get::<_, i32>("x")?;
+            let y = pos.get::<_, i32>("y")?;
 
-            let size = table.get::<_, LuaTable>("size").unwrap();
-            let width = size.get::<_, i32>("width").unwrap();
-            let height = size.get::<_, i32>("height").unwrap();
+            let size = table.get::<_, LuaTable>("size")?;
+            let width = size.get::<_, i32>("width")?;
+            let height = size.get::<_, i32>("height")?;
 
-            let id = table.get::<_, usize>("id").unwrap();
-            let title = table.get::<_, String>("title").unwrap();
-            let parent = table.get::<_, Option<usize>>("parent").unwrap();
-            let last_update = table.get::<_, f64>("last_update").unwrap();
+            let id = table.get::<_, usize>("id")?;
+            let title = table.get::<_, String>("title")?;
+            let parent = table.get::<_, Option<usize>>("parent")?;
+            let last_update = table.get::<_, f64>("last_update")?;
 
             Ok(Self {
                 id,
@@ -152,7 +451,7 @@ impl<'lua> FromLua<'lua> for FrameMessenger {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ZIndex {
     Back,
     Auto,
@@ -162,9 +461,9 @@ pub enum ZIndex {
 impl<'lua> ToLua<'lua> for ZIndex {
     fn to_lua(self, lua: Context<'lua>) -> rlua::Result<Value<'lua>> {
         match self {
-            ZIndex::Back => Ok(Value::String(lua.create_string("back").unwrap())),
-            ZIndex::Auto => Ok(Value::String(lua.create_string("auto").unwrap())),
-            ZIndex::Front => Ok(Value::String(lua.create_string("front").unwrap())),
+            ZIndex::Back => Ok(Value::String(lua.create_string("back")?)),
+            ZIndex::Auto => Ok(Value::String(lua.create_string("auto")?)),
+            ZIndex::Front => Ok(Value::String(lua.create_string("front")?)),
         }
     }
 }
@@ -185,6 +484,11 @@ pub struct FrameOptions {
     pub can_close: bool,
     pub z_lock: ZIndex,
     pub parent: Option<usize>,
+    /// Whether the compositor draws a title bar and border around the frame
+    pub decorated: bool,
+    pub buffers: BufferMode,
+    /// Whether the compositor renders everything beneath the frame into an extra buffer
+    pub backdrop: bool,
 }
 
 impl FrameOptions {
@@ -201,6 +505,9 @@ impl FrameOptions {
             can_close: self.can_close,
             z_lock: self.z_lock.clone(),
             parent: self.parent,
+            decorated: self.decorated,
+            buffers: self.buffers,
+            backdrop: self.backdrop,
         }
     }
 }
@@ -219,6 +526,9 @@ impl Default for FrameOptions {
             can_close: false,
             z_lock: ZIndex::Auto,
             parent: None,
+            decorated: true,
+            buffers: BufferMode::Single,
+            backdrop: false,
         }
     }
 }
@@ -238,7 +548,7 @@ impl<'lua> FromLua<'lua> for FrameOptions {
                 can_close: value.get("can_close").unwrap_or_default(),
                 z_lock: {
                     match value.get("z_lock") {
-                        Ok(Value::String(s)) => match s.to_str().unwrap() {
+                        Ok(Value::String(s)) => match s.to_str().unwrap_or_default() {
                             "back" => ZIndex::Back,
                             "auto" => ZIndex::Auto,
                             "front" => ZIndex::Front,
@@ -248,6 +558,9 @@ impl<'lua> FromLua<'lua> for FrameOptions {
                     }
                 },
                 parent: value.get("parent").unwrap_or_default(),
+                decorated: value.get("decorated").unwrap_or(true),
+                buffers: value.get::<_, usize>("buffers").ok().and_then(BufferMode::from_count).unwrap_or(BufferMode::Single),
+                backdrop: value.get("backdrop").unwrap_or_default(),
             }.normalise()),
             _ => Err(rlua::Error::FromLuaConversionError {
                 message: Some("Expected Table".to_owned()),
@@ -258,6 +571,14 @@ impl<'lua> FromLua<'lua> for FrameOptions {
     }
 }
 
+/// Mixes two premultiplied pixels, taking `alpha / 256` of `src`
+fn fade(src: u32, dst: u32, alpha: u32) -> u32 {
+    let rb = ((src & 0x00ff00ff) * alpha + (dst & 0x00ff00ff) * (256 - alpha)) >> 8;
+    let ag = ((src >> 8) & 0x00ff00ff) * alpha + ((dst >> 8) & 0x00ff00ff) * (256 - alpha);
+
+    (rb & 0x00ff00ff) | (ag & 0xff00ff00)
+}
+
 fn parse_coord(str: &str) -> (i32, i32) {
     let mut parts = str.split(',');
     let x = parts.next().unwrap_or("").parse::<i32>().unwrap_or(0);
@@ -267,7 +588,12 @@ fn parse_coord(str: &str) -> (i32, i32) {
 
 impl FrameOptions {
     pub fn from_string(src: &str) -> Result<Self, String> {
-        let mut options = FrameOptions::default();
+        Self::from_string_with(src, FrameOptions::default())
+    }
+
+    /// Applies the options in `src` on top of `defaults`
+    pub fn from_string_with(src: &str, defaults: FrameOptions) -> Result<Self, String> {
+        let mut options = defaults;
 
         for option in src.split('&') {
             match option {
@@ -275,16 +601,10 @@ impl FrameOptions {
                 "resize" => options.can_resize = true,
                 "close" => options.can_close = true,
                 "transparent" => options.transparent = true,
+                "borderless" => options.decorated = false,
+                "backdrop" => options.backdrop = true,
                 "z-lock=back" => options.z_lock = ZIndex::Back,
                 "z-lock=front" => options.z_lock = ZIndex::Front,
-                "parent" => {
-                    let mut parts = option.split('=');
-                    let _ = parts.next();
-                    options.parent = match parts.next() {
-                        Some(str) => Some(str.parse().unwrap_or(0usize)),
-                        None => None
-                    };
-                }
                 _ => {
                     let mut parts = option.split('=');
                     let key = parts.next().unwrap_or("");
@@ -295,6 +615,9 @@ impl FrameOptions {
                         "size" => options.size = Size2D::from(parse_coord(value)).max(options.min_size).min(options.max_size),
                         "pos" => options.pos = Point2D::from(parse_coord(value)),
                         "title" => options.title = value.to_owned(),
+                        "parent" => options.parent = Some(value.parse().map_err(|_| format!("Invalid parent {}", value))?),
+                        "buffers" => options.buffers = value.parse().ok().and_then(BufferMode::from_count)
+                            .ok_or_else(|| format!("Invalid buffer count {}, expected 1, 2 or 3", value))?,
                         key => {
                             return Err(format!("Invalid option {} or invalid value", key));
                         }
@@ -316,7 +639,6 @@ impl FrameOptions {
 pub enum FrameRequest {
     Create(FrameOptions),
     Destroy(usize),
-    // TODO: Add request to fill background buffer
     SetTitle(usize, String),
     SetSize(usize, Size2D<i32, UnknownUnit>),
     SetPos(usize, Point2D<i32, UnknownUnit>),
@@ -326,11 +648,15 @@ pub enum FrameRequest {
     SetCanResize(usize, bool),
     SetCanClose(usize, bool),
     SetTransparent(usize, bool),
+    SetOpacity(usize, f32),
+    /// Lets a user parent frames to this one and see it in their backdrops
+    Grant(usize, u32),
+    Revoke(usize, u32),
 }
 
 #[derive(Debug, Clone)]
-pub enum FrameEvent<'a> {
-    Created(&'a FrameMessenger),
+pub enum FrameEvent {
+    Created(FrameMessenger),
     Destroyed(usize),
     TitleChanged(usize, String),
     SizeChanged(usize, Size2D<i32, UnknownUnit>),
@@ -341,64 +667,167 @@ pub enum FrameEvent<'a> {
     CanResizeChanged(usize, bool),
     CanCloseChanged(usize, bool),
     TransparentChanged(usize, bool),
+    OpacityChanged(usize, f32),
+}
+
+impl FrameRequest {
+    /// Parses control commands written to a frame handle, in the same `key=value&...` grammar used to open frames:
+    /// `title=`, `pos=x,y` relative to the parent, `size=w,h`, `parent=id`, `z-lock=back|auto|front`, the flags `minimise`,
+    /// `resize`, `close` and `transparent`, which take an optional `=true` or `=false`, `grant=uid` and `revoke=uid`,
+    /// and `request-close`, which destroys the frame.
+    pub fn from_string(id: usize, src: &str) -> Result<Vec<FrameRequest>, String> {
+        let pair = |value: &str| value.split_once(',')
+            .and_then(|(x, y)| Some((x.trim().parse::<i32>().ok()?, y.trim().parse::<i32>().ok()?)));
+
+        src.trim().split('&')
+            .filter(|command| !command.is_empty())
+            .map(|command| {
+                let (key, value) = match command.split_once('=') {
+                    Some((key, value)) => (key, Some(value)),
+                    None => (command, None)
+                };
+
+                let flag = || match value {
+                    None | Some("true") => Ok(true),
+                    Some("false") => Ok(false),
+                    Some(value) => Err(format!("Invalid value {} for {}", value, key))
+                };
+                let value = || value.ok_or_else(|| format!("{} requires a value", key));
+
+                Ok(match key {
+                    "title" => FrameRequest::SetTitle(id, value()?.to_owned()),
+                    "pos" => FrameRequest::SetPos(id, Point2D::from(pair(value()?).ok_or_else(|| format!("Invalid position {}", value().unwrap_or_default()))?)),
+                    "size" => match pair(value()?) {
+                        Some((width, height)) if width >= 0 && height >= 0 => FrameRequest::SetSize(id, Size2D::new(width, height)),
+                        _ => return Err(format!("Invalid size {}", value()?))
+                    },
+                    "parent" => FrameRequest::SetParent(id, value()?.parse().map_err(|_| format!("Invalid parent {}", value().unwrap_or_default()))?),
+                    "z-lock" => FrameRequest::SetZIndex(id, match value()? {
+                        "back" => ZIndex::Back,
+                        "auto" => ZIndex::Auto,
+                        "front" => ZIndex::Front,
+                        z_lock => return Err(format!("Invalid z-lock {}", z_lock))
+                    }),
+                    "minimise" => FrameRequest::SetCanMinimise(id, flag()?),
+                    "resize" => FrameRequest::SetCanResize(id, flag()?),
+                    "close" => FrameRequest::SetCanClose(id, flag()?),
+                    "transparent" => FrameRequest::SetTransparent(id, flag()?),
+                    "grant" => FrameRequest::Grant(id, value()?.parse().map_err(|_| format!("Invalid uid {}", value().unwrap_or_default()))?),
+                    "revoke" => FrameRequest::Revoke(id, value()?.parse().map_err(|_| format!("Invalid uid {}", value().unwrap_or_default()))?),
+                    "request-close" => FrameRequest::Destroy(id),
+                    key => return Err(format!("Invalid command {}", key))
+                })
+            })
+            .collect()
+    }
+
+    /// The frame the request concerns. `Create` requests don't concern an existing frame.
+    pub fn id(&self) -> Option<usize> {
+        match self {
+            FrameRequest::Create(_) => None,
+            FrameRequest::Destroy(id)
+            | FrameRequest::SetTitle(id, _)
+            | FrameRequest::SetSize(id, _)
+            | FrameRequest::SetPos(id, _)
+            | FrameRequest::SetParent(id, _)
+            | FrameRequest::SetZIndex(id, _)
+            | FrameRequest::SetCanMinimise(id, _)
+            | FrameRequest::SetCanResize(id, _)
+            | FrameRequest::SetCanClose(id, _)
+            | FrameRequest::SetTransparent(id, _)
+            | FrameRequest::SetOpacity(id, _)
+            | FrameRequest::Grant(id, _)
+            | FrameRequest::Revoke(id, _) => Some(*id),
+        }
+    }
+}
+
+impl FrameEvent {
+    /// The frame the event concerns
+    pub fn id(&self) -> usize {
+        match self {
+            FrameEvent::Created(frame) => frame.id,
+            FrameEvent::Destroyed(id)
+            | FrameEvent::TitleChanged(id, _)
+            | FrameEvent::SizeChanged(id, _)
+            | FrameEvent::PosChanged(id, _)
+            | FrameEvent::ParentChanged(id, _)
+            | FrameEvent::ZIndexChanged(id, _)
+            | FrameEvent::CanMinimiseChanged(id, _)
+            | FrameEvent::CanResizeChanged(id, _)
+            | FrameEvent::CanCloseChanged(id, _)
+            | FrameEvent::TransparentChanged(id, _)
+            | FrameEvent::OpacityChanged(id, _) => *id,
+        }
+    }
 }
 
 impl<'lua> FromLua<'lua> for FrameRequest {
     fn from_lua(value: Value<'lua>, lua: Context<'lua>) -> rlua::Result<Self> {
         if let Value::Table(value) = value {
-            let action = value.get::<_, String>("action").unwrap();
-            let id = value.get::<_, usize>("id").unwrap();
+            let action = value.get::<_, String>("action")?;
+            // `create` is the only action which doesn't refer to an existing frame
+            let id = || value.get::<_, usize>("id");
 
             match action.as_str() {
                 "create" => {
-                    let options = value.get::<_, String>("options").unwrap();
-                    let options = FrameOptions::from_string(&options).unwrap();
+                    let options = value.get::<_, String>("options")?;
+                    let options = FrameOptions::from_string(&options).map_err(rlua::Error::RuntimeError)?;
                     Ok(FrameRequest::Create(options))
                 }
-                "destroy" => Ok(FrameRequest::Destroy(id)),
+                "destroy" => Ok(FrameRequest::Destroy(id()?)),
                 "set-title" => {
-                    let title = value.get::<_, String>("title").unwrap();
-                    Ok(FrameRequest::SetTitle(id, title))
+                    let title = value.get::<_, String>("title")?;
+                    Ok(FrameRequest::SetTitle(id()?, title))
                 }
                 "set-size" => {
-                    let size = value.get::<_, Table>("size").unwrap();
-                    let size = Size2D::new(size.get("x").unwrap(), size.get("y").unwrap());
-                    Ok(FrameRequest::SetSize(id, size))
+                    let size = value.get::<_, Table>("size")?;
+                    let size = Size2D::new(size.get("x")?, size.get("y")?);
+                    Ok(FrameRequest::SetSize(id()?, size))
                 }
                 "set-pos" => {
-                    let pos = value.get::<_, Table>("pos").unwrap();
-                    let pos = Point2D::new(pos.get("x").unwrap(), pos.get("y").unwrap());
-                    Ok(FrameRequest::SetPos(id, pos))
+                    let pos = value.get::<_, Table>("pos")?;
+                    let pos = Point2D::new(pos.get("x")?, pos.get("y")?);
+                    Ok(FrameRequest::SetPos(id()?, pos))
                 }
                 "set-parent" => {
-                    let parent = value.get::<_, usize>("parent").unwrap();
-                    Ok(FrameRequest::SetParent(id, parent))
+                    let parent = value.get::<_, usize>("parent")?;
+                    Ok(FrameRequest::SetParent(id()?, parent))
                 }
                 "set-z-index" => {
-                    let z_index = value.get::<_, String>("z-index").unwrap();
+                    let z_index = value.get::<_, String>("z-index")?;
                     let z_index = match z_index.as_str() {
                         "back" => ZIndex::Back,
                         "front" => ZIndex::Front,
                         _ => ZIndex::Auto
                     };
-                    Ok(FrameRequest::SetZIndex(id, z_index))
+                    Ok(FrameRequest::SetZIndex(id()?, z_index))
                 }
                 "set-can-minimise" => {
-                    let can_minimise = value.get::<_, bool>("can-minimise").unwrap();
-                    Ok(FrameRequest::SetCanMinimise(id, can_minimise))
+                    let can_minimise = value.get::<_, bool>("can-minimise")?;
+                    Ok(FrameRequest::SetCanMinimise(id()?, can_minimise))
                 }
                 "set-can-resize" => {
-                    let can_resize = value.get::<_, bool>("can-resize").unwrap();
-                    Ok(FrameRequest::SetCanResize(id, can_resize))
+                    let can_resize = value.get::<_, bool>("can-resize")?;
+                    Ok(FrameRequest::SetCanResize(id()?, can_resize))
                 }
                 "set-can-close" => {
-                    let can_close = value.get::<_, bool>("can-close").unwrap();
-                    Ok(FrameRequest::SetCanClose(id, can_close))
+                    let can_close = value.get::<_, bool>("can-close")?;
+                    Ok(FrameRequest::SetCanClose(id()?, can_close))
                 }
                 "set-transparent" => {
-                    let transparent = value.get::<_, bool>("transparent").unwrap();
-                    Ok(FrameRequest::SetTransparent(id, transparent))
+                    let transparent = value.get::<_, bool>("transparent")?;
+                    Ok(FrameRequest::SetTransparent(id()?, transparent))
                 }
+                "set-opacity" => {
+                    let opacity = value.get::<_, f32>("opacity")?;
+                    if !opacity.is_finite() {
+                        return Err(rlua::Error::RuntimeError(format!("Invalid opacity {}", opacity)));
+                    }
+                    Ok(FrameRequest::SetOpacity(id()?, opacity))
+                }
+                "grant" => Ok(FrameRequest::Grant(id()?, value.get::<_, u32>("uid")?)),
+                "revoke" => Ok(FrameRequest::Revoke(id()?, value.get::<_, u32>("uid")?)),
                 _ => Err(rlua::Error::FromLuaConversionError {
                     from: "FrameRequest",
                     to: "FrameRequest",
@@ -415,9 +844,9 @@ impl<'lua> FromLua<'lua> for FrameRequest {
     }
 }
 
-impl<'lua, 'a> ToLua<'lua> for FrameEvent<'a> {
+impl<'lua> ToLua<'lua> for FrameEvent {
     fn to_lua(self, lua: Context<'lua>) -> rlua::Result<Value<'lua>> {
-        let table = lua.create_table().unwrap();
+        let table = lua.create_table()?;
 
         table.set("event", match self {
             FrameEvent::Created(_) => "created",
@@ -431,56 +860,61 @@ impl<'lua, 'a> ToLua<'lua> for FrameEvent<'a> {
             FrameEvent::CanResizeChanged(_, _) => "can-resize-changed",
             FrameEvent::CanCloseChanged(_, _) => "can-close-changed",
             FrameEvent::TransparentChanged(_, _) => "transparent-changed",
-        }).unwrap();
+            FrameEvent::OpacityChanged(_, _) => "opacity-changed",
+        })?;
 
         match self {
-            FrameEvent::Created(frame) => table.set("frame", frame.clone()).unwrap(),
-            FrameEvent::Destroyed(id) => table.set("id", id).unwrap(),
+            FrameEvent::Created(frame) => table.set("frame", frame)?,
+            FrameEvent::Destroyed(id) => table.set("id", id)?,
             FrameEvent::TitleChanged(id, title) => {
-                table.set("id", id).unwrap();
-                table.set("title", title).unwrap();
+                table.set("id", id)?;
+                table.set("title", title)?;
             }
             FrameEvent::SizeChanged(id, size) => {
-                table.set("id", id).unwrap();
+                table.set("id", id)?;
                 table.set("size", {
-                    let mut table = lua.create_table().unwrap();
-                    table.set("width", size.width).unwrap();
-                    table.set("height", size.height).unwrap();
+                    let mut table = lua.create_table()?;
+                    table.set("width", size.width)?;
+                    table.set("height", size.height)?;
                     table
-                }).unwrap();
+                })?;
             }
             FrameEvent::PosChanged(id, pos) => {
-                table.set("id", id).unwrap();
+                table.set("id", id)?;
                 table.set("pos", {
-                    let mut table = lua.create_table().unwrap();
-                    table.set("x", pos.x).unwrap();
-                    table.set("y", pos.y).unwrap();
+                    let mut table = lua.create_table()?;
+                    table.set("x", pos.x)?;
+                    table.set("y", pos.y)?;
                     table
-                }).unwrap();
+                })?;
             }
             FrameEvent::ParentChanged(id, parent) => {
-                table.set("id", id).unwrap();
-                table.set("parent", parent).unwrap();
+                table.set("id", id)?;
+                table.set("parent", parent)?;
             }
             FrameEvent::ZIndexChanged(id, z_index) => {
-                table.set("id", id).unwrap();
-                table.set("z-index", z_index).unwrap();
+                table.set("id", id)?;
+                table.set("z-index", z_index)?;
             }
             FrameEvent::CanMinimiseChanged(id, can_minimise) => {
-                table.set("id", id).unwrap();
-                table.set("can-minimise", can_minimise).unwrap();
+                table.set("id", id)?;
+                table.set("can-minimise", can_minimise)?;
             }
             FrameEvent::CanResizeChanged(id, can_resize) => {
-                table.set("id", id).unwrap();
-                table.set("can-resize", can_resize).unwrap();
+                table.set("id", id)?;
+                table.set("can-resize", can_resize)?;
             }
             FrameEvent::CanCloseChanged(id, can_close) => {
-                table.set("id", id).unwrap();
-                table.set("can-close", can_close).unwrap();
+                table.set("id", id)?;
+                table.set("can-close", can_close)?;
             }
             FrameEvent::TransparentChanged(id, transparent) => {
-                table.set("id", id).unwrap();
-                table.set("transparent", transparent).unwrap();
+                table.set("id", id)?;
+                table.set("transparent", transparent)?;
+            }
+            FrameEvent::OpacityChanged(id, opacity) => {
+                table.set("id", id)?;
+                table.set("opacity", opacity)?;
             }
         }
 
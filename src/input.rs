@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::os::fd::{FromRawFd, RawFd};
+
+use syscall::O_NONBLOCK;
+
+const EVENT_KEY: i64 = 1;
+const EVENT_MOUSE: i64 = 2;
+const EVENT_BUTTON: i64 = 3;
+const EVENT_SCROLL: i64 = 4;
+const EVENT_MOUSE_RELATIVE: i64 = 11;
+
+pub const BUTTON_LEFT: u8 = 1;
+pub const BUTTON_MIDDLE: u8 = 1 << 1;
+pub const BUTTON_RIGHT: u8 = 1 << 2;
+
+/// While held, frames can be moved with the left button and resized with the right button from anywhere inside them
+pub const SCANCODE_SUPER: u8 = 0x5B;
+
+/// The layout of events produced by the `input:` scheme, matching `orbclient::Event`
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct RawInput {
+    code: i64,
+    a: i64,
+    b: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent {
+    Key { character: char, scancode: u8, pressed: bool },
+    Mouse { x: i32, y: i32 },
+    MouseRelative { dx: i32, dy: i32 },
+    /// A bitmask of the held buttons, see `BUTTON_LEFT`, `BUTTON_MIDDLE` and `BUTTON_RIGHT`
+    Buttons(u8),
+    Scroll { x: i32, y: i32 },
+}
+
+impl RawInput {
+    fn parse(&self) -> Option<InputEvent> {
+        match self.code {
+            EVENT_KEY => Some(InputEvent::Key {
+                character: char::from_u32(self.a as u32).unwrap_or('\0'),
+                scancode: self.b as u8,
+                pressed: self.b & (1 << 8) != 0,
+            }),
+            EVENT_MOUSE => Some(InputEvent::Mouse { x: self.a as i32, y: self.b as i32 }),
+            EVENT_MOUSE_RELATIVE => Some(InputEvent::MouseRelative { dx: self.a as i32, dy: self.b as i32 }),
+            EVENT_BUTTON => Some(InputEvent::Buttons(self.a as u8 & (BUTTON_LEFT | BUTTON_MIDDLE | BUTTON_RIGHT))),
+            EVENT_SCROLL => Some(InputEvent::Scroll { x: self.a as i32, y: self.b as i32 }),
+            _ => None
+        }
+    }
+}
+
+/// A non-blocking handle on the input scheme
+pub struct Input {
+    file: File,
+}
+
+impl Input {
+    pub fn open(path: &str) -> Result<Input, String> {
+        syscall::open(path, syscall::O_RDONLY | syscall::O_CLOEXEC | O_NONBLOCK)
+            .map(|fd| Self { file: unsafe { File::from_raw_fd(fd as RawFd) } })
+            .map_err(|err| format!("Unable to open input {}: {}", path, err))
+    }
+
+    /// Returns every event which arrived since the last poll
+    pub fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+        let mut buf = [RawInput::default(); 64];
+
+        loop {
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, mem::size_of_val(&buf))
+            };
+
+            let count = match self.file.read(bytes) {
+                Ok(count) if count > 0 => count / mem::size_of::<RawInput>(),
+                _ => break
+            };
+
+            events.extend(buf[..count].iter().filter_map(RawInput::parse));
+
+            if count < buf.len() {
+                break;
+            }
+        }
+
+        events
+    }
+}
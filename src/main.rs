@@ -10,23 +10,43 @@ use crate::compositor::Compositor;
 use crate::plugin::PluginManager;
 
 mod compositor;
+mod damage;
+mod decoration;
 mod display;
+mod event;
+mod focus;
 mod frame;
+mod input;
+mod overlay;
 mod config;
 mod bin;
 mod plugin;
+mod stack;
 
 fn main() {
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Failed to load config: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if config.displays.is_empty() {
+        eprintln!("No displays configured");
+        std::process::exit(1);
+    }
+
     redox_daemon::Daemon::new(move |daemon| {
         daemon.ready().expect("erika: failed to notify parent");
 
-        let config = config::load()
-            .expect("Failed to fetch config");
         let mut mgr = PluginManager::new(config.clone())
             .expect("Failed to create Plugin Manager");
 
-        mgr.load_plugins(&config.plugins)
-            .expect("Failed to load plugins");
+        // plugins which fail to load are skipped
+        if let Err(err) = mgr.load_plugins(&config.plugins) {
+            eprintln!("Failed to load plugins:\n{}", err);
+        }
 
         mgr.run();
 
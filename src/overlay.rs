@@ -0,0 +1,49 @@
+use raqote::{BlendMode, DrawTarget, IntRect};
+
+/// A buffer painted by a plugin. Overlays are drawn above every frame and persist until they are replaced or cleared.
+pub struct Overlay {
+    pub rect: IntRect,
+    /// The path of the plugin which painted the overlay
+    pub owner: String,
+    surface: DrawTarget,
+}
+
+impl Overlay {
+    /// Takes a buffer of unpremultiplied ARGB pixels which must cover `rect` exactly
+    pub fn new(buffer: Vec<u32>, rect: IntRect, owner: &str) -> Result<Self, String> {
+        if rect.is_empty() {
+            return Err(format!("Overlay has no area: {}x{}", rect.width(), rect.height()));
+        }
+
+        let len = rect.width() as usize * rect.height() as usize;
+        if buffer.len() != len {
+            return Err(format!("Buffer of {} pixels does not match overlay size {}x{}", buffer.len(), rect.width(), rect.height()));
+        }
+
+        let buffer = buffer.into_iter()
+            .map(premultiply)
+            .collect();
+
+        Ok(Self {
+            rect,
+            owner: owner.to_owned(),
+            surface: DrawTarget::from_backing(rect.width(), rect.height(), buffer),
+        })
+    }
+
+    /// Blends the part of the overlay which lies within `clip`
+    pub fn draw(&self, surface: &mut DrawTarget, clip: &IntRect) {
+        let Some(visible) = self.rect.intersection(clip) else {
+            return;
+        };
+
+        surface.blend_surface(&self.surface, visible.translate(-self.rect.min.to_vector()), visible.min, BlendMode::SrcOver);
+    }
+}
+
+fn premultiply(argb: u32) -> u32 {
+    let a = argb >> 24;
+    let channel = |shift: u32| (((argb >> shift) & 0xff) * a + 127) / 255;
+
+    (a << 24) | (channel(16) << 16) | (channel(8) << 8) | channel(0)
+}
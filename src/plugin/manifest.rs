@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rlua::{HookTriggers, Lua, StdLib, Table};
+
+use crate::config::{Capability, PluginLimits};
+use crate::plugin::PLUGIN_API_VERSION;
+use crate::plugin::plugin::HOOK_INTERVAL;
+
+/// Describes a plugin. Read from a sidecar next to the plugin, `foo.lua` having `foo.manifest.lua`:
+///
+/// ```lua
+/// name = "tiling"
+/// version = "1.2.0"
+/// api = 1                                  -- the `PLUGIN_API_VERSION` the plugin was written against
+/// capabilities = { "frames", "input" }     -- only granted if the config grants them too
+/// depends = { "panel" }                    -- names of plugins which must be loaded first
+/// ```
+///
+/// Plugins without a manifest are named after their file, and get whichever capabilities the config grants them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: Option<String>,
+    pub api: u32,
+    /// The capabilities the plugin asks for. `None` if it doesn't say.
+    pub capabilities: Option<Vec<Capability>>,
+    pub depends: Vec<String>,
+}
+
+impl Manifest {
+    pub fn path_for(plugin: &str) -> PathBuf {
+        Path::new(plugin).with_extension("manifest.lua")
+    }
+
+    /// Reads the plugin's manifest, or makes one up if it has none.
+    /// The manifest runs within the plugin's limits, and can't load other files, catch errors or match patterns.
+    pub fn load(plugin: &str, limits: PluginLimits) -> Result<Manifest, String> {
+        let mut manifest = Manifest {
+            name: Path::new(plugin).file_stem().map_or(plugin.to_owned(), |i| i.to_string_lossy().into_owned()),
+            version: None,
+            api: PLUGIN_API_VERSION,
+            capabilities: None,
+            depends: Vec::new(),
+        };
+
+        let path = Manifest::path_for(plugin);
+        if !path.is_file() {
+            return Ok(manifest);
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|err| format!("Unable to read manifest {}: {}", path.display(), err))?;
+
+        let lua = Lua::new_with(StdLib::BASE | StdLib::STRING | StdLib::TABLE | StdLib::MATH);
+
+        let mut remaining = limits.instructions as i64;
+        lua.set_hook(HookTriggers { every_nth_instruction: Some(HOOK_INTERVAL), ..HookTriggers::default() }, move |_ctx, _debug| {
+            remaining -= HOOK_INTERVAL as i64;
+            match remaining > 0 {
+                true => Ok(()),
+                false => Err(rlua::Error::RuntimeError("instruction limit exceeded".to_owned())),
+            }
+        });
+        lua.set_memory_limit(Some(limits.memory));
+
+        lua.context(|ctx| -> rlua::Result<()> {
+            for name in ["dofile", "loadfile", "load", "pcall", "xpcall"] {
+                ctx.globals().set(name, rlua::Value::Nil)?;
+            }
+
+            // a pattern match can't be interrupted by the instruction limit
+            let string: Table = ctx.globals().get("string")?;
+            for name in ["find", "match", "gmatch", "gsub"] {
+                string.set(name, rlua::Value::Nil)?;
+            }
+
+            ctx.load(&source).set_name(&format!("={}", path.display()))?.exec()?;
+
+            let globals: Table = ctx.globals();
+            manifest.name = globals.get::<_, Option<String>>("name")?.unwrap_or(manifest.name.clone());
+            manifest.version = globals.get::<_, Option<String>>("version")?;
+            manifest.api = globals.get::<_, Option<u32>>("api")?.unwrap_or(manifest.api);
+            manifest.depends = globals.get::<_, Option<Vec<String>>>("depends")?.unwrap_or_default();
+
+            if let Some(capabilities) = globals.get::<_, Option<Vec<String>>>("capabilities")? {
+                manifest.capabilities = Some(capabilities.iter()
+                    .map(|i| Capability::from_str(i).ok_or_else(|| rlua::Error::RuntimeError(format!("unknown capability '{}'", i))))
+                    .collect::<rlua::Result<_>>()?);
+            }
+
+            Ok(())
+        }).map_err(|err| format!("Invalid manifest {}: {}", path.display(), err))?;
+
+        Ok(manifest)
+    }
+
+    /// Whether the plugin asks for the capability, or doesn't say what it needs
+    pub fn requests(&self, capability: Capability) -> bool {
+        self.capabilities.as_ref().map_or(true, |i| i.contains(&capability))
+    }
+}
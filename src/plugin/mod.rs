@@ -1,28 +1,42 @@
 use std::cell::{Ref, RefCell};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
 use std::mem::MaybeUninit;
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::{mpsc, Mutex};
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use euclid::{Box2D, Point2D, Size2D, UnknownUnit};
 use raqote::IntPoint;
+use rlua::{Context, ToLua, Value};
 
-use crate::compositor::{Compositor, MAX_FPS};
-use crate::config::Config;
-use crate::frame::{FrameMessenger, FrameOptions};
+use crate::compositor::Compositor;
+use crate::config;
+use crate::config::{Capability, Config};
+use crate::frame::{FrameEvent, FrameMessenger, FrameOptions, FrameRequest, Owner};
+use crate::plugin::manifest::Manifest;
 use crate::plugin::plugin::{MessageID, Plugin};
 
+mod manifest;
 mod plugin;
 
+/// How often the config file, and plugins when `watch_plugins` is set, are checked for changes
+pub const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bumped whenever the API below changes. Plugins whose manifest asks for a newer version aren't loaded.
+pub const PLUGIN_API_VERSION: u32 = 1;
+
 /// # public plugin API
 /// ## these functions can be exported as _hooks_
 /// 1. Frame
 ///     * `OnFrameCreate(frame)`
 ///     * `OnFrameDestroy(frame)`
 ///     * `OnFrameUpdate(frame)`
-/// 2. Mouse
+///     * `OnFrameEvent(event)` - a property of a frame changed, eg. `{event = "size-changed", id, size}`
+/// 2. Mouse - these and the keyboard hooks need the `input` capability
 ///     * `OnMouseMove(mouse)`
 ///     * `OnMouseDown(button)`
 ///     * `OnMouseUp(button)`
@@ -32,44 +46,70 @@ mod plugin;
 ///     * `OnKeyUp(key)`
 /// 5. Plugin
 ///     * `OnPluginLoad(plugin)`
-///     * `OnBeforePluginUnload(plugin)`
+///     * `OnBeforePluginUnload(plugin)` - the plugin's frames and overlays are removed once it returns. Plugins are unloaded
+///       when removed from the config, when reloaded, eg. by `watch_plugins`, and when they exceed their limits.
 ///
 /// ## these functions can be called from the plugin
+/// Each takes an optional callback last, which is called exactly once: with the result, or with `nil` and an error message.
 /// 1. Frames
-///     * `create_frame(options)`
-///     * `get_frame_by_id(id)`
-///     * `close_frame(id)`
+///     * `create_frame(options, callback)`
+///     * `get_frame_by_id(id, callback)`
+///     * `close_frame(id, callback)`
+///     * `request_frame(request, callback)` - change a frame, eg. `{action = "set-title", id, title}`. The callback receives the frame, or nothing if it was destroyed.
+///       Plugins may only change, raise and lower frames they created, unless the config grants them the `frames` capability.
+///     * `get_stack(callback)` - frame ids from bottom to top
+///     * `raise_frame(id, callback)` / `lower_frame(id, callback)` - restack within the frame's `ZIndex` layer
 /// 2. Input
-///     * `get_mouse() -> Mouse`
-///     * `get_keys() -> Keys`
-/// 3. Painting
-///     * `paint_buffer(buffer, pos, size)`
+///     * `get_mouse(callback)` - the callback receives a `Mouse`
+///     * `get_keys(callback)` - the callback receives `Keys`
+/// 3. Painting - needs the `overlays` capability
+///     * `paint_buffer(buffer, pos, size, callback)` - ARGB pixels drawn above all frames until replaced or cleared
+///     * `clear_buffer(pos, size, callback)`
+///
+/// ## `comp`
+/// The functions above are also in the `comp` table without the callback. Called from a coroutine, they wait for the
+/// compositor and return what the callback would have received. Handlers run as coroutines, and `comp.spawn(fn, ...)` starts one.
+/// ```lua
+/// function on_plugin_load()
+///     local frame = comp.create_frame({ size = { width = 320, height = 240 } })
+///     comp.request_frame({ action = "set-title", id = frame.id, title = "Hello" })
+/// end
+/// ```
 ///
 /// ## objects
 /// * `Frame {id, title, x, y, w, h, parent() -> Frame, get_buffer() -> Buffer, send_event(Event), close()}`
 /// * `Event {type, x, y, button, key, delta}`
 /// * `Buffer u32[]`
-/// * `Mouse {x, y, buttons, scroll_delta}`
-/// * `Keys {pressed, released}`
+/// * `Mouse {x, y, buttons, scroll_delta = {x, y}}` - the wheel's movement since input was last polled
+/// * `Keys {pressed, released}` - scancodes of the held keys, and of those released since input was last polled
 
 struct Channel {
     pub response: Sender<(MessageID, PluginResponse)>,
     pub receiver: Receiver<(MessageID, PluginRequest)>,
 }
 
-pub struct PluginManager<'a, 'b> {
+pub struct PluginManager {
     loaded: Vec<(Plugin, Channel)>,
-    comp: Compositor<'a, 'b>,
+    comp: Compositor,
     // event_receiver: Receiver<PluginEvent>
     event_receiver: Rc<Mutex<VecDeque<PluginEvent>>>,
+    config_modified: Option<SystemTime>,
+    last_config_check: Instant,
+    /// When each loaded plugin's file was last modified, for `watch_plugins`
+    plugins_modified: HashMap<String, Option<SystemTime>>,
+    last_plugin_check: Instant,
 }
 
-impl<'a, 'b> PluginManager<'a, 'b> {
+impl PluginManager {
     pub fn new(config: Config) -> Result<Self, String> {
-        let (comp, receiver) = Compositor::<'a, 'b>::new(config.clone())
+        let (comp, receiver) = Compositor::new(config.clone())
             .expect("Failed to create Compositor");
 
         let mut mgr = Self {
+            config_modified: config.path.as_ref().and_then(|path| modified(path)),
+            last_config_check: Instant::now(),
+            plugins_modified: HashMap::new(),
+            last_plugin_check: Instant::now(),
             loaded: Vec::new(),
             comp,
             event_receiver: receiver,
@@ -78,12 +118,26 @@ impl<'a, 'b> PluginManager<'a, 'b> {
         Ok(mgr)
     }
 
+    /// Loads the plugin with the capabilities both its manifest asks for and the config grants.
+    /// Dependencies aren't loaded, see `load_plugins`.
     pub fn load(&mut self, path: &str) -> Result<(), String> {
+        // watched even if it fails to load, so it's retried once fixed
+        self.plugins_modified.insert(path.to_owned(), modified(Path::new(path)));
+
+        let manifest = Manifest::load(path, self.comp.config.plugin_limits)?;
+        if manifest.api > PLUGIN_API_VERSION {
+            return Err(format!("Plugin {} needs API version {}, but only {} is supported", path, manifest.api, PLUGIN_API_VERSION));
+        }
+
         let request = mpsc::channel();
         let response = mpsc::channel();
 
-        let mut plugin = Plugin::new(path, request.0, response.1)?;
-        plugin.run().unwrap();
+        let capabilities = self.capabilities(path, &manifest);
+        let stdlib = capabilities.contains(&Capability::Stdlib);
+        let mut plugin = Plugin::new(path, request.0, response.1, manifest, self.comp.config.plugin_limits, stdlib)?;
+
+        self.comp.plugin_capabilities.insert(path.to_owned(), capabilities);
+        plugin.run()?;
         self.loaded.push((plugin, Channel {
             response: response.0,
             receiver: request.1,
@@ -92,28 +146,168 @@ impl<'a, 'b> PluginManager<'a, 'b> {
         Ok(())
     }
 
+    /// Drops the plugin's Lua state, after giving it a chance to clean up. Its frames and overlays are removed.
+    /// The plugin's file is still watched until it's removed from the config.
+    pub fn unload(&mut self, path: &str) -> Result<(), String> {
+        let Some(index) = self.loaded.iter().position(|(plugin, _)| plugin.path == path) else {
+            return Err(format!("Plugin {} is not loaded", path));
+        };
+
+        let (plugin, _) = self.loaded.remove(index);
+        plugin.on_before_plugin_unload();
+        drop(plugin);
+
+        self.comp.remove_plugin(path);
+
+        Ok(())
+    }
+
+    /// The capabilities the config grants the plugin which its manifest asks for
+    fn capabilities(&self, path: &str, manifest: &Manifest) -> Vec<Capability> {
+        self.comp.config.capabilities.get(path)
+            .map(|granted| granted.iter().copied().filter(|i| manifest.requests(*i)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Unloads the plugin, if it's loaded, and runs its file again
+    pub fn reload(&mut self, path: &str) -> Result<(), String> {
+        if self.loaded.iter().any(|(plugin, _)| plugin.path == path) {
+            self.unload(path)?;
+        }
+
+        self.load(path)?;
+        println!("Reloaded plugin {}", path);
+
+        Ok(())
+    }
+
+    /// Re-reads the config file and applies it. Frames are kept, and only plugins which were added or removed are (un)loaded.
+    pub fn reload_config(&mut self) -> Result<(), String> {
+        let Some(path) = self.comp.config.path.clone() else {
+            return Err("The compositor was not started with a config file".to_owned());
+        };
+
+        let config = config::load_from(&path)?;
+
+        let removed: Vec<String> = self.comp.config.plugins.iter()
+            .filter(|i| !config.plugins.contains(i))
+            .cloned()
+            .collect();
+        let added: Vec<String> = config.plugins.iter()
+            .filter(|i| !self.comp.config.plugins.contains(i))
+            .cloned()
+            .collect();
+
+        for plugin in removed {
+            self.plugins_modified.remove(&plugin);
+            if let Err(err) = self.unload(&plugin) {
+                eprintln!("{}", err);
+            }
+        }
+
+        self.comp.apply_config(config);
+
+        // grants may have changed, though `stdlib` only takes effect once the plugin is reloaded
+        let granted: Vec<(String, Vec<Capability>)> = self.loaded.iter()
+            .map(|(plugin, _)| (plugin.path.clone(), self.capabilities(&plugin.path, &plugin.manifest)))
+            .collect();
+        self.comp.plugin_capabilities.extend(granted);
+
+        if let Err(err) = self.load_plugins(&added) {
+            eprintln!("{}", err);
+        }
+
+        println!("Reloaded config {}", path.display());
+
+        Ok(())
+    }
+
+    /// Polls the config file's modification time, at most once per `CONFIG_POLL_INTERVAL`
+    fn config_changed(&mut self) -> bool {
+        if self.last_config_check.elapsed() < CONFIG_POLL_INTERVAL {
+            return false;
+        }
+
+        self.last_config_check = Instant::now();
+
+        let Some(path) = self.comp.config.path.as_ref() else {
+            return false;
+        };
+
+        let modified = modified(path);
+        if modified.is_some() && modified != self.config_modified {
+            self.config_modified = modified;
+            return true;
+        }
+
+        false
+    }
+
+    /// Plugins whose files changed since they were loaded. Checked at most once per `CONFIG_POLL_INTERVAL`.
+    fn plugins_changed(&mut self) -> Vec<String> {
+        if !self.comp.config.watch_plugins || self.last_plugin_check.elapsed() < CONFIG_POLL_INTERVAL {
+            return Vec::new();
+        }
+
+        self.last_plugin_check = Instant::now();
+
+        self.plugins_modified.iter()
+            .filter(|(path, previous)| {
+                let modified = modified(Path::new(path));
+                modified.is_some() && modified != **previous
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
     pub(crate) fn run(&mut self) {
         loop {
             let now = std::time::Instant::now();
+
+            if self.config_changed() {
+                if let Err(err) = self.reload_config() {
+                    eprintln!("Failed to reload config: {}", err);
+                }
+            }
+
+            for path in self.plugins_changed() {
+                if let Err(err) = self.reload(&path) {
+                    eprintln!("Failed to reload plugin: {}", err);
+                }
+            }
+
             self.comp.tick();
-            if let Some(e) = self.event_receiver.lock().unwrap().pop_back() {
+            loop {
+                let Some(e) = self.event_receiver.lock().unwrap().pop_front() else {
+                    break;
+                };
                 self.event(e);
             }
 
             self.read_requests();
+            self.unload_disabled();
             let elapsed = now.elapsed();
-            if elapsed < MAX_FPS {
-                thread::sleep(MAX_FPS - elapsed);
+            let frame_time = self.comp.config.frame_time();
+            if elapsed < frame_time {
+                thread::sleep(frame_time - elapsed);
             }
         }
     }
 
     pub fn event(&self, event: PluginEvent) {
+        let input = matches!(event, PluginEvent::OnMouseMove(..) | PluginEvent::OnMouseDown(_) | PluginEvent::OnMouseUp(_)
+            | PluginEvent::OnMouseScroll(..) | PluginEvent::OnKeyDown(_) | PluginEvent::OnKeyUp(_));
+
         for (plugin, _) in &self.loaded {
+            if input && !self.comp.plugin_has(&plugin.path, Capability::Input) {
+                continue;
+            }
+
             match event.clone() {
                 PluginEvent::OnFrameCreate(frame) => plugin.on_frame_create(frame),
                 PluginEvent::OnFrameDestroy(frame) => plugin.on_frame_destroy(frame),
                 PluginEvent::OnFrameUpdate(frame) => plugin.on_frame_update(frame),
+                PluginEvent::OnFrameEvent(event) => plugin.on_frame_event(event),
                 PluginEvent::OnMouseMove(x, y) => plugin.on_mouse_move(x, y),
                 PluginEvent::OnMouseDown(btn) => plugin.on_mouse_down(btn),
                 PluginEvent::OnMouseUp(btn) => plugin.on_mouse_up(btn),
@@ -126,55 +320,109 @@ impl<'a, 'b> PluginManager<'a, 'b> {
         }
     }
 
+    /// Answers every request the plugins have sent. Failures are passed on to the plugin rather than reported here.
     pub fn read_requests(&mut self) {
         for (plugin, channel) in self.loaded.iter_mut() {
-            if let Ok((id, req)) = channel.receiver.try_recv() {
-                match req {
-                    PluginRequest::CreateFrame(options) => {
-                        println!("{:?}", options);
-                        if let Ok(frame) = self.comp.mk_frame(options) {
-                            channel.response.send((id, PluginResponse::Frame(frame.get_messenger()))).unwrap();
-                        } else {
-                            eprintln!("Failed to create frame");
-                        }
-                    },
-                    PluginRequest::CloseFrame(id) => {
-                        self.comp.close_frame(id).unwrap()
-                    },
-                    PluginRequest::GetFrameById(frame_id) => {
-                        if let Some(frame) = self.comp.get_frame_by_id(frame_id) {
-                            channel.response.send((id, PluginResponse::Frame(frame.get_messenger()))).unwrap();
-                        } else {
-                            eprintln!("Failed to get frame by id");
-                        }
-                    },
-                    // PluginRequest::GetMouse() => {
-                    //     let mouse = self.comp.get_mouse();
-                    //     channel.response.send((id, PluginResponse::Mouse(mouse))).unwrap();
-                    // },
-                    // PluginRequest::GetKeys() => {
-                    //     let keys = self.comp.get_keys();
-                    //     channel.response.send((id, PluginResponse::Keys(keys))).unwrap();
-                    // },
-                    PluginRequest::PaintBuffer(buffer, pos, size) => {
-                        self.comp.paint_buffer(buffer, Box2D::from_origin_and_size(pos, size));
-                    },
-                    _ => todo!()
-                }
+            // plugins can only change frames they created, unless granted `Capability::Frames`
+            let caller = Owner::Plugin(plugin.path.clone());
+
+            while let Ok((id, req)) = channel.receiver.try_recv() {
+                let response = match req {
+                    PluginRequest::CreateFrame(options) => self.comp.apply(&caller, FrameRequest::Create(options))
+                        .map(|frame| frame_response(&self.comp, frame)),
+                    PluginRequest::GetFrameById(frame) => self.comp.get_frame_by_id(frame)
+                        .map(|frame| PluginResponse::Frame(frame.get_messenger()))
+                        .ok_or(syscall::Error::new(syscall::ENOENT)),
+                    PluginRequest::CloseFrame(frame) => self.comp.apply(&caller, FrameRequest::Destroy(frame))
+                        .map(|_| PluginResponse::None()),
+                    PluginRequest::Frame(request) => self.comp.apply(&caller, request)
+                        .map(|frame| frame_response(&self.comp, frame)),
+                    PluginRequest::GetStack() => Ok(PluginResponse::Stack(self.comp.get_stack())),
+                    PluginRequest::RaiseFrame(frame) => self.comp.authorise(&caller, frame)
+                        .and_then(|_| self.comp.raise_frame(frame))
+                        .map(|_| PluginResponse::Stack(self.comp.get_stack())),
+                    PluginRequest::LowerFrame(frame) => self.comp.authorise(&caller, frame)
+                        .and_then(|_| self.comp.lower_frame(frame))
+                        .map(|_| PluginResponse::Stack(self.comp.get_stack())),
+                    PluginRequest::GetMouse() | PluginRequest::GetKeys() if !self.comp.plugin_has(&plugin.path, Capability::Input) => Err(syscall::Error::new(syscall::EPERM)),
+                    PluginRequest::PaintBuffer(..) | PluginRequest::ClearBuffer(..) if !self.comp.plugin_has(&plugin.path, Capability::Overlays) => Err(syscall::Error::new(syscall::EPERM)),
+                    PluginRequest::GetMouse() => Ok(PluginResponse::Mouse(self.comp.cursor, self.comp.focus.buttons, self.comp.focus.scroll)),
+                    PluginRequest::GetKeys() => Ok(PluginResponse::Keys(self.comp.focus.keys.clone(), self.comp.focus.released.clone())),
+                    PluginRequest::PaintBuffer(buffer, pos, size) => overlay_rect(pos, size).and_then(|rect| self.comp.paint_buffer(&plugin.path, buffer, rect))
+                        .map(|_| PluginResponse::None()),
+                    PluginRequest::ClearBuffer(pos, size) => overlay_rect(pos, size).and_then(|rect| self.comp.clear_buffer(&plugin.path, rect))
+                        .map(|_| PluginResponse::None()),
+                };
+
+                // the plugin holds the receiver for as long as it's loaded
+                let _ = channel.response.send((id, response.unwrap_or_else(|err| PluginResponse::Error(err.to_string()))));
             }
 
             plugin.receive_responses();
         }
     }
 
+    /// Unloads plugins which exceeded their limits
+    fn unload_disabled(&mut self) {
+        let disabled: Vec<String> = self.loaded.iter()
+            .filter(|(plugin, _)| plugin.is_disabled())
+            .map(|(plugin, _)| plugin.path.clone())
+            .collect();
+
+        for path in disabled {
+            if self.unload(&path).is_ok() {
+                eprintln!("Plugin {} exceeded its limits and was unloaded", path);
+            }
+        }
+    }
+
+    /// Loads the plugins, each after those it depends on. Plugins whose dependencies can't be loaded are skipped.
     pub fn load_plugins(&mut self, plugins: &Vec<String>) -> Result<(), String> {
-        for i in plugins {
-            let Ok(plugin) = self.load(i) else {
-                return Err(format!("Failed to load plugin {}", i));
-            };
+        let mut errors = Vec::new();
+        let mut pending = Vec::new();
+
+        for path in plugins {
+            self.plugins_modified.insert(path.clone(), modified(Path::new(path)));
+
+            match Manifest::load(path, self.comp.config.plugin_limits) {
+                Ok(manifest) => pending.push((path.clone(), manifest)),
+                Err(err) => errors.push(err),
+            }
         }
 
-        Ok(())
+        // load whichever plugin comes first with all its dependencies loaded, until none are left
+        while let Some(index) = pending.iter().position(|(_, manifest)| manifest.depends.iter()
+            .all(|name| self.loaded.iter().any(|(plugin, _)| plugin.manifest.name == *name))) {
+            let (path, _) = pending.remove(index);
+            if let Err(err) = self.load(&path) {
+                errors.push(err);
+            }
+        }
+
+        for (path, manifest) in pending {
+            let missing: Vec<&str> = manifest.depends.iter()
+                .filter(|name| !self.loaded.iter().any(|(plugin, _)| plugin.manifest.name == **name))
+                .map(|name| name.as_str())
+                .collect();
+            errors.push(format!("Plugin {} depends on {}, which could not be loaded", path, missing.join(", ")));
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("\n")),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|i| i.modified()).ok()
+}
+
+/// Fails with `EINVAL` if the rect would reach past `i32::MAX`
+fn overlay_rect(pos: Point2D<i32, UnknownUnit>, size: Size2D<i32, UnknownUnit>) -> syscall::Result<Box2D<i32, UnknownUnit>> {
+    match (pos.x.checked_add(size.width), pos.y.checked_add(size.height)) {
+        (Some(x), Some(y)) => Ok(Box2D::new(pos, Point2D::new(x, y))),
+        _ => Err(syscall::Error::new(syscall::EINVAL)),
     }
 }
 
@@ -183,6 +431,7 @@ pub enum PluginEvent {
     OnFrameCreate(FrameMessenger),
     OnFrameDestroy(FrameMessenger),
     OnFrameUpdate(FrameMessenger),
+    OnFrameEvent(FrameEvent),
     OnMouseMove(i32, i32),
     OnMouseDown(u8),
     OnMouseUp(u8),
@@ -198,16 +447,65 @@ pub enum PluginRequest {
     CreateFrame(FrameOptions),
     GetFrameById(usize),
     CloseFrame(usize),
+    Frame(FrameRequest),
+    GetStack(),
+    RaiseFrame(usize),
+    LowerFrame(usize),
     GetMouse(),
     GetKeys(),
     PaintBuffer(Vec<u32>, Point2D<i32, UnknownUnit>, Size2D<i32, UnknownUnit>),
+    ClearBuffer(Point2D<i32, UnknownUnit>, Size2D<i32, UnknownUnit>),
 }
 
+/// Passed to the callback the plugin gave along with its request. `Error` is passed as `nil, message`.
 #[derive(Debug, Clone)]
 pub enum PluginResponse {
     Frame(FrameMessenger),
-    // Mouse(IntPoint, u8, (f32, f32)),
-    // Keys(Vec<u8>, Vec<u8>),
+    Stack(Vec<usize>),
+    /// The cursor, the held buttons and how far the wheel scrolled since input was last polled
+    Mouse(IntPoint, u8, (i32, i32)),
+    /// The held keys, and the keys released since input was last polled
+    Keys(Vec<u8>, Vec<u8>),
     Buffer(Vec<u32>),
     None(),
+    Error(String),
+}
+
+impl<'lua> ToLua<'lua> for PluginResponse {
+    fn to_lua(self, lua: Context<'lua>) -> rlua::Result<Value<'lua>> {
+        match self {
+            PluginResponse::Frame(frame) => frame.to_lua(lua),
+            PluginResponse::Stack(stack) => stack.to_lua(lua),
+            PluginResponse::Mouse(pos, buttons, scroll) => {
+                let table = lua.create_table()?;
+                table.set("x", pos.x)?;
+                table.set("y", pos.y)?;
+                table.set("buttons", buttons)?;
+
+                let delta = lua.create_table()?;
+                delta.set("x", scroll.0)?;
+                delta.set("y", scroll.1)?;
+                table.set("scroll_delta", delta)?;
+
+                Ok(Value::Table(table))
+            }
+            PluginResponse::Keys(pressed, released) => {
+                let table = lua.create_table()?;
+                table.set("pressed", pressed)?;
+                table.set("released", released)?;
+
+                Ok(Value::Table(table))
+            }
+            PluginResponse::Buffer(buffer) => buffer.to_lua(lua),
+            PluginResponse::None() | PluginResponse::Error(_) => Ok(Value::Nil),
+        }
+    }
+}
+
+/// The frame, or nothing if it's gone
+fn frame_response(comp: &Compositor, id: usize) -> PluginResponse {
+    match comp.get_frame_by_id(id) {
+        Some(frame) => PluginResponse::Frame(frame.get_messenger()),
+        None => PluginResponse::None(),
+    }
 }
@@ -5,16 +5,32 @@ use std::io::Read;
 use std::ops::{Add, AddAssign};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::time::Duration;
 use euclid::default::{Point2D, Size2D};
 use raqote::Point;
+use rlua::{HookTriggers, StdLib};
 
-use crate::frame::{Frame, FrameMessenger, FrameOptions, FrameRequest};
+use crate::config::PluginLimits;
+use crate::frame::{Frame, FrameEvent, FrameMessenger, FrameOptions, FrameRequest};
 use crate::plugin::{PluginRequest, PluginResponse};
+use crate::plugin::manifest::Manifest;
 
 pub(crate) type MessageID = rlua::RegistryKey;
 
+/// How many instructions run between checks of a plugin's instruction budget
+pub(super) const HOOK_INTERVAL: u32 = 1000;
+
+/// Lua run before each plugin, defining the `comp` table
+const PRELUDE: &str = include_str!("prelude.lua");
+
+/// Lua run when a plugin is created, so it can't catch the errors which stop it
+const SANDBOX: &str = include_str!("sandbox.lua");
+
+/// The message Lua raises when an allocation fails
+const MEMORY_ERROR: &[u8] = b"not enough memory";
+
 /// Channels allow us to communicate across threads.
 /// In the circumstance where Lua happens to call a provided method from another thread, this will cause race conditions, so we fall back to a callback-architecture
 /// Implemented by messaging the main thread which does the execution and awaiting the response.
@@ -48,40 +64,108 @@ impl<T> Counter<T> where T: Add<Output=T> + Clone {
 }
 
 pub struct Plugin {
+    pub path: String,
+    pub manifest: Manifest,
     pub source: File,
     pub lua: rlua::Lua,
 
     registry_key: Arc<rlua::RegistryKey>,
     channel: Channel,
+
+    limits: PluginLimits,
+    /// Instructions the plugin may still run before it's stopped. Reset each time the plugin is called into.
+    budget: Arc<AtomicI64>,
+    /// Set once the plugin exceeds its limits, after which it isn't called into again
+    disabled: Arc<AtomicBool>,
 }
 
 macro_rules! handler {
     ($name:ident$(,$arg:ident: $val:ty)*) => {
         pub fn $name(&self$(, $arg:$val)*) {
-            if let Err(err) = self.lua.context(|ctx| -> rlua::Result<()> {
+            self.enter(stringify!($name), |ctx| {
                 if let Ok(handler) = ctx.named_registry_value::<_, rlua::Function>(stringify!($name)) {
-                    handler.call::<_, ()>(($($arg,)*))
-                        .unwrap();
+                    // as a coroutine, the handler can wait on the compositor through `comp`
+                    ctx.create_thread(handler)?.resume::<_, ()>(($($arg,)*))?;
                 }
                 Ok(())
-            }) {
-                eprintln!("\nPlugin Error({}): {:?}", stringify!($name), err);
-            }
+            });
         }
     };
 }
 macro_rules! set_handler {
     ($ctx:expr,$name:ident) => {
-        if let Ok(handler) = $ctx.globals().get::<_, rlua::Function>(stringify!($name)) { $ctx.set_named_registry_value(stringify!($name), handler).unwrap(); };
+        if let Ok(handler) = $ctx.globals().get::<_, rlua::Function>(stringify!($name)) { $ctx.set_named_registry_value(stringify!($name), handler)?; };
     }
 }
 
+fn out_of_memory(err: &rlua::Error) -> bool {
+    match err {
+        rlua::Error::MemoryError(_) => true,
+        rlua::Error::CallbackError { cause, .. } => out_of_memory(cause),
+        _ => false,
+    }
+}
+
+/// Registers the callback, if any, and sends the request it waits on
+fn send<'lua>(ctx: rlua::Context<'lua>, request: &Sender<(MessageID, PluginRequest)>, callback: Option<rlua::Function<'lua>>, req: PluginRequest) -> rlua::Result<()> {
+    let registry_key = ctx.create_registry_value(callback)?;
+    request.send((registry_key, req))
+        .map_err(|_| rlua::Error::RuntimeError("The compositor is no longer accepting requests".to_owned()))
+}
+
 impl Plugin {
-    pub fn new(path: &str, sender: Sender<(MessageID, PluginRequest)>, receiver: Receiver<(MessageID, PluginResponse)>) -> Result<Self, String> {
-        let lua = rlua::Lua::new();
-        let reg = lua.context(|ctx| ctx.create_registry_value(rlua::Value::Table(ctx.create_table().unwrap()))).unwrap();
+    /// Plugins get a restricted standard library without `io`, `os`, `package`, `dofile` and `loadfile`, unless given `stdlib`.
+    /// Either way, the string functions taking patterns are removed, as the instruction limit can't interrupt them.
+    pub fn new(path: &str, sender: Sender<(MessageID, PluginRequest)>, receiver: Receiver<(MessageID, PluginResponse)>, manifest: Manifest, limits: PluginLimits, stdlib: bool) -> Result<Self, String> {
+        let lua = match stdlib {
+            true => rlua::Lua::new(),
+            false => rlua::Lua::new_with(StdLib::BASE | StdLib::COROUTINE | StdLib::TABLE | StdLib::STRING | StdLib::UTF8 | StdLib::MATH),
+        };
+
+        if !stdlib {
+            lua.context(|ctx| -> rlua::Result<()> {
+                ctx.globals().set("dofile", rlua::Value::Nil)?;
+                ctx.globals().set("loadfile", rlua::Value::Nil)
+            }).map_err(|err| format!("Unable to create plugin {}: {}", path, err))?;
+        }
+
+        let disabled = Arc::new(AtomicBool::new(false));
+
+        let exceeded = Arc::clone(&disabled);
+        lua.context(|ctx| -> rlua::Result<()> {
+            let exceeded = ctx.create_function(move |_ctx, err: rlua::Value| {
+                if let rlua::Value::String(err) = err {
+                    if err.as_bytes() == MEMORY_ERROR {
+                        exceeded.store(true, Ordering::Relaxed);
+                    }
+                }
+
+                Ok(exceeded.load(Ordering::Relaxed))
+            })?;
+
+            ctx.load(SANDBOX).set_name("=sandbox")?.call(exceeded)
+        }).map_err(|err| format!("Unable to create plugin {}: {}", path, err))?;
+
+        // a runaway plugin is stopped rather than stalling the compositor
+        let budget = Arc::new(AtomicI64::new(0));
+        let (remaining, exceeded) = (Arc::clone(&budget), Arc::clone(&disabled));
+        lua.set_hook(HookTriggers { every_nth_instruction: Some(HOOK_INTERVAL), ..HookTriggers::default() }, move |_ctx, _debug| {
+            match remaining.fetch_sub(HOOK_INTERVAL as i64, Ordering::Relaxed) > 0 {
+                true => Ok(()),
+                false => {
+                    exceeded.store(true, Ordering::Relaxed);
+                    Err(rlua::Error::RuntimeError("instruction limit exceeded".to_owned()))
+                }
+            }
+        });
+        lua.set_memory_limit(Some(limits.memory));
+
+        let reg = lua.context(|ctx| ctx.create_table().and_then(|table| ctx.create_registry_value(table)))
+            .map_err(|err| format!("Unable to create plugin {}: {}", path, err))?;
 
         Ok(Self {
+            path: path.to_owned(),
+            manifest,
             source: File::open(path).map_err(|_| format!("Unable to open plugin {}", path))?,
             lua,
             registry_key: Arc::new(reg),
@@ -91,62 +175,82 @@ impl Plugin {
                 receiver,
                 reg_key: HashMap::new(),
             },
+            limits,
+            budget,
+            disabled,
         })
     }
 
     pub fn run(&mut self) -> Result<(), String> {
         let mut source = String::new();
         self.source.read_to_string(&mut source)
-            .expect("Failed to read plugin source");
+            .map_err(|err| format!("Unable to read plugin {}: {}", self.path, err))?;
 
-        if let Err(err) = self.lua.context(|ctx| -> rlua::Result<()> {
+        self.enter("run", |ctx| {
             let globals = ctx.globals();
 
             let request = self.channel.request.clone();
-            globals.set("create_frame", ctx.create_function(move |ctx, (options, on_create): (FrameOptions, rlua::Function)| -> rlua::Result<()> {
-                let registry_key = ctx.create_registry_value(on_create).unwrap();
-                request.send((registry_key, PluginRequest::CreateFrame(options))).unwrap();
-                Ok(())
-            }).unwrap()).unwrap();
+            globals.set("create_frame", ctx.create_function(move |ctx, (options, callback): (FrameOptions, Option<rlua::Function>)| {
+                send(ctx, &request, callback, PluginRequest::CreateFrame(options))
+            })?)?;
 
             let request = self.channel.request.clone();
-            globals.set("get_frame_by_id", ctx.create_function(move |ctx, (id, callback): (usize, rlua::Function)| -> rlua::Result<()> {
-                let registry_key = ctx.create_registry_value(id).unwrap();
-                request.send((registry_key, PluginRequest::GetFrameById(id))).unwrap();
-                Ok(())
-            }).unwrap()).unwrap();
+            globals.set("get_frame_by_id", ctx.create_function(move |ctx, (id, callback): (usize, Option<rlua::Function>)| {
+                send(ctx, &request, callback, PluginRequest::GetFrameById(id))
+            })?)?;
 
             let request = self.channel.request.clone();
-            globals.set("close_frame", ctx.create_function(move |ctx, (id, callback): (usize, rlua::Function)| -> rlua::Result<()> {
-                let registry_key = ctx.create_registry_value(id).unwrap();
-                request.send((registry_key, PluginRequest::CloseFrame(id))).unwrap();
-                Ok(())
-            }).unwrap()).unwrap();
+            globals.set("close_frame", ctx.create_function(move |ctx, (id, callback): (usize, Option<rlua::Function>)| {
+                send(ctx, &request, callback, PluginRequest::CloseFrame(id))
+            })?)?;
 
             let request = self.channel.request.clone();
-            globals.set("get_mouse", ctx.create_function(move |ctx, (callback): (rlua::Function)| -> rlua::Result<()> {
-                let registry_key = ctx.create_registry_value(callback).unwrap();
-                request.send((registry_key, PluginRequest::GetMouse())).unwrap();
-                Ok(())
-            }).unwrap()).unwrap();
+            globals.set("request_frame", ctx.create_function(move |ctx, (frame_request, callback): (FrameRequest, Option<rlua::Function>)| {
+                send(ctx, &request, callback, PluginRequest::Frame(frame_request))
+            })?)?;
 
             let request = self.channel.request.clone();
-            globals.set("get_keys", ctx.create_function(move |ctx, (callback): (rlua::Function)| -> rlua::Result<()> {
-                let registry_key = ctx.create_registry_value(callback).unwrap();
-                request.send((registry_key, PluginRequest::GetKeys())).unwrap();
-                Ok(())
-            }).unwrap()).unwrap();
+            globals.set("get_stack", ctx.create_function(move |ctx, callback: Option<rlua::Function>| {
+                send(ctx, &request, callback, PluginRequest::GetStack())
+            })?)?;
 
             let request = self.channel.request.clone();
-            globals.set("paint_buffer", ctx.create_function(move |ctx, (buffer, point, size, callback): (Vec<u32>, rlua::Table, rlua::Table, rlua::Function)| -> rlua::Result<()> {
-                let registry_key = ctx.create_registry_value(callback).unwrap();
+            globals.set("raise_frame", ctx.create_function(move |ctx, (id, callback): (usize, Option<rlua::Function>)| {
+                send(ctx, &request, callback, PluginRequest::RaiseFrame(id))
+            })?)?;
 
+            let request = self.channel.request.clone();
+            globals.set("lower_frame", ctx.create_function(move |ctx, (id, callback): (usize, Option<rlua::Function>)| {
+                send(ctx, &request, callback, PluginRequest::LowerFrame(id))
+            })?)?;
+
+            let request = self.channel.request.clone();
+            globals.set("get_mouse", ctx.create_function(move |ctx, callback: Option<rlua::Function>| {
+                send(ctx, &request, callback, PluginRequest::GetMouse())
+            })?)?;
+
+            let request = self.channel.request.clone();
+            globals.set("get_keys", ctx.create_function(move |ctx, callback: Option<rlua::Function>| {
+                send(ctx, &request, callback, PluginRequest::GetKeys())
+            })?)?;
+
+            let request = self.channel.request.clone();
+            globals.set("paint_buffer", ctx.create_function(move |ctx, (buffer, point, size, callback): (Vec<u32>, rlua::Table, rlua::Table, Option<rlua::Function>)| {
                 let p = Point2D::new(point.get::<_, i32>("x").unwrap_or(0), point.get::<_, i32>("y").unwrap_or(0));
                 let s = Size2D::new(size.get::<_, i32>("width").unwrap_or(0), size.get::<_, i32>("height").unwrap_or(0));
 
-                request.send((registry_key, PluginRequest::PaintBuffer(buffer, p, s))).unwrap();
-                Ok(())
-            }).unwrap()).unwrap();
+                send(ctx, &request, callback, PluginRequest::PaintBuffer(buffer, p, s))
+            })?)?;
+
+            let request = self.channel.request.clone();
+            globals.set("clear_buffer", ctx.create_function(move |ctx, (point, size, callback): (rlua::Table, rlua::Table, Option<rlua::Function>)| {
+                let p = Point2D::new(point.get::<_, i32>("x").unwrap_or(0), point.get::<_, i32>("y").unwrap_or(0));
+                let s = Size2D::new(size.get::<_, i32>("width").unwrap_or(0), size.get::<_, i32>("height").unwrap_or(0));
+
+                send(ctx, &request, callback, PluginRequest::ClearBuffer(p, s))
+            })?)?;
+
+            ctx.load(PRELUDE).set_name("=prelude")?.exec()?;
 
             if let Err(err) = ctx.load(&source).exec() {
                 return Err(err);
@@ -156,6 +260,7 @@ impl Plugin {
             set_handler!(ctx, on_frame_create);
             set_handler!(ctx, on_frame_destroy);
             set_handler!(ctx, on_frame_update);
+            set_handler!(ctx, on_frame_event);
             // Mouse
             set_handler!(ctx, on_mouse_move);
             set_handler!(ctx, on_mouse_down);
@@ -169,9 +274,7 @@ impl Plugin {
             set_handler!(ctx, on_before_plugin_unload);
 
             Ok(())
-        }) {
-            eprintln!("\nPlugin Error\n: {:?}", err);
-        }
+        });
 
         // call the plugin load handler
         self.on_plugin_load();
@@ -179,24 +282,51 @@ impl Plugin {
         Ok(())
     }
 
+    /// Passes each response to the callback waiting on it
     pub fn receive_responses(&mut self) {
-        while let Ok((id, response)) = self.channel.receiver.try_recv() {
-            if let Err(err) = match response {
-                PluginResponse::Frame(req) => self.lua.context(|ctx| if let Ok(handler) = ctx.registry_value::<rlua::Function>(&id) {
-                    handler.call::<_, ()>((req, ))
-                } else {
-                    Ok(())
-                }),
-                _ => todo!()
-            } {
-                eprintln!("\nPlugin Error\n: {:?}", err);
+        while let Ok((key, response)) = self.channel.receiver.try_recv() {
+            self.enter("response", |ctx| {
+                let callback = ctx.registry_value::<Option<rlua::Function>>(&key)?;
+                ctx.remove_registry_value(key)?;
+
+                let Some(callback) = callback else {
+                    return Ok(());
+                };
+
+                match response {
+                    PluginResponse::Error(err) => callback.call::<_, ()>((rlua::Value::Nil, err)),
+                    response => callback.call::<_, ()>(response),
+                }
+            });
+        }
+    }
+
+    /// Calls into the plugin with a fresh instruction budget. If the plugin exceeds its limits, it's disabled.
+    fn enter(&self, name: &str, call: impl FnOnce(rlua::Context) -> rlua::Result<()>) {
+        if self.is_disabled() {
+            return;
+        }
+
+        self.budget.store(self.limits.instructions as i64, Ordering::Relaxed);
+
+        if let Err(err) = self.lua.context(call) {
+            eprintln!("\nPlugin Error({}): {:?}", name, err);
+
+            if out_of_memory(&err) {
+                self.disabled.store(true, Ordering::Relaxed);
             }
         }
     }
 
+    /// Whether the plugin exceeded its limits. Disabled plugins should be unloaded.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+
     handler!(on_frame_create, frame: FrameMessenger);
     handler!(on_frame_destroy, frame: FrameMessenger);
     handler!(on_frame_update, frame: FrameMessenger);
+    handler!(on_frame_event, event: FrameEvent);
 
     handler!(on_mouse_move, x: i32, y: i32);
     handler!(on_mouse_down, button: u8);
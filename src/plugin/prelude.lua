@@ -0,0 +1,45 @@
+-- Run before each plugin. `comp` wraps the callback API so that coroutines can wait on the compositor instead:
+--
+--     local frame = comp.create_frame({ size = { width = 320, height = 240 } })
+--     comp.request_frame({ action = "set-pos", id = frame.id, pos = { x = 40, y = 40 } })
+--
+-- Handlers already run as coroutines. Like the callbacks, calls return `nil, message` if the compositor reports an error.
+-- They aren't raised, as rlua's `pcall` can't be yielded across.
+
+comp = {}
+
+local function resume(co, ...)
+    local ok, err = coroutine.resume(co, ...)
+    if not ok then
+        error(err, 0)
+    end
+end
+
+for _, name in ipairs({
+    "create_frame", "get_frame_by_id", "close_frame", "request_frame", "get_stack",
+    "raise_frame", "lower_frame", "get_mouse", "get_keys", "paint_buffer", "clear_buffer",
+}) do
+    local request = _G[name]
+
+    comp[name] = function(...)
+        local co, main = coroutine.running()
+        if main then
+            error("comp." .. name .. " can only be called from a handler or comp.spawn", 2)
+        end
+
+        local args = table.pack(...)
+        args.n = args.n + 1
+        args[args.n] = function(result, err)
+            resume(co, result, err)
+        end
+
+        request(table.unpack(args, 1, args.n))
+
+        return coroutine.yield()
+    end
+end
+
+-- Starts a coroutine, eg. from a callback, which can use `comp`
+function comp.spawn(fn, ...)
+    resume(coroutine.create(fn), ...)
+end
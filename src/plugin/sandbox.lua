@@ -0,0 +1,37 @@
+-- Run before anything else in a plugin. Errors raised once the plugin exceeds its limits can't be caught,
+-- so `pcall`, `xpcall` and `coroutine.resume` pass them on instead of returning them. Lua patterns aren't available.
+--
+-- `exceeded(err)` is given by the compositor. It reports whether the plugin has exceeded its limits.
+
+local exceeded = ...
+local protect, protect_with, resume = pcall, xpcall, coroutine.resume
+
+local function check(ok, ...)
+    if not ok and exceeded((...)) then
+        error((...), 0)
+    end
+
+    return ok, ...
+end
+
+function pcall(...)
+    return check(protect(...))
+end
+
+-- the handler isn't run for those errors either, as it could keep the plugin running
+function xpcall(fn, handler, ...)
+    return check(protect_with(fn, function(err)
+        if exceeded(err) then
+            return err
+        end
+
+        return handler(err)
+    end, ...))
+end
+
+function coroutine.resume(...)
+    return check(resume(...))
+end
+
+-- the limits are only checked between Lua instructions, and a single pattern match can take exponential time
+string.find, string.match, string.gmatch, string.gsub = nil, nil, nil, nil
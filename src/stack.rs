@@ -0,0 +1,100 @@
+use crate::frame::ZIndex;
+
+/// The order in which frames are drawn, from bottom to top.
+/// Frames are grouped into one layer per `ZIndex`, so that raising or lowering a frame never moves it out of its layer.
+#[derive(Debug, Clone, Default)]
+pub struct Stack {
+    back: Vec<usize>,
+    auto: Vec<usize>,
+    front: Vec<usize>,
+}
+
+impl Stack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn layer_mut(&mut self, z_index: ZIndex) -> &mut Vec<usize> {
+        match z_index {
+            ZIndex::Back => &mut self.back,
+            ZIndex::Auto => &mut self.auto,
+            ZIndex::Front => &mut self.front,
+        }
+    }
+
+    pub fn layer_of(&self, id: usize) -> Option<ZIndex> {
+        if self.back.contains(&id) {
+            Some(ZIndex::Back)
+        } else if self.auto.contains(&id) {
+            Some(ZIndex::Auto)
+        } else if self.front.contains(&id) {
+            Some(ZIndex::Front)
+        } else {
+            None
+        }
+    }
+
+    /// Places the frame on top of its layer
+    pub fn insert(&mut self, id: usize, z_index: ZIndex) {
+        self.remove(id);
+        self.layer_mut(z_index).push(id);
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<ZIndex> {
+        let z_index = self.layer_of(id)?;
+        self.layer_mut(z_index).retain(|i| *i != id);
+        Some(z_index)
+    }
+
+    /// Moves the frame to the top of its layer. Returns false if the frame isn't stacked.
+    pub fn raise(&mut self, id: usize) -> bool {
+        match self.layer_of(id) {
+            Some(z_index) => {
+                self.insert(id, z_index);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Moves the frame directly above `below`, into `below`'s layer. Returns false if `below` isn't stacked.
+    pub fn place_above(&mut self, id: usize, below: usize) -> bool {
+        if id == below {
+            return false;
+        }
+
+        let Some(z_index) = self.layer_of(below) else {
+            return false;
+        };
+
+        self.remove(id);
+        let layer = self.layer_mut(z_index);
+        let index = layer.iter().position(|i| *i == below).map_or(layer.len(), |i| i + 1);
+        layer.insert(index, id);
+
+        true
+    }
+
+    /// Moves the frame to the bottom of its layer. Returns false if the frame isn't stacked.
+    pub fn lower(&mut self, id: usize) -> bool {
+        match self.remove(id) {
+            Some(z_index) => {
+                self.layer_mut(z_index).insert(0, id);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Frame ids from bottom to top
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item=usize> + '_ {
+        self.back.iter()
+            .chain(self.auto.iter())
+            .chain(self.front.iter())
+            .copied()
+    }
+
+    pub fn to_vec(&self) -> Vec<usize> {
+        self.iter().collect()
+    }
+}